@@ -6,19 +6,43 @@ use super::config::*;
 use std::sync::{Arc, Mutex};
 
 mod sounds;
+mod block;
+mod envelope;
 mod dasp_test;
 mod general;
+mod synth;
+mod fm;
+mod wavetable;
+mod granular;
+mod physical;
+mod drums;
+mod record;
+mod input;
+mod processor;
+mod chain;
+mod nodes;
+mod voices;
 pub(crate) mod parameters;
+pub(crate) mod pool;
 
 use parameters::Parameters;
+pub use sounds::{VoiceKey, Playback};
+pub use block::Block;
+pub use record::RECORD_SIGNAL;
+pub use input::Take;
+pub use chain::Chain;
+pub use pool::Sample;
 
 pub struct AudioData<R> {
-    pub(crate) sounds: sounds::Sounds,
+    chain: Chain,
+    // what the audio thread is done with goes here to be freed
+    garbage: Sender<chain::Garbage>,
     params: Parameters<R>,
     pub(crate) audio_tx: Sender<AudioMessage>,
     pub(crate) audio_rx: Receiver<AudioMessage>,
-    dasp_test: dasp_test::DaspTestData,
-    general: general::General
+    // only the output stream records
+    recorder: Option<record::Recorder>,
+    input: Option<input::Input>
 }
 
 impl<R> Default for AudioData<R>
@@ -30,29 +54,46 @@ impl<R> Default for AudioData<R>
         params.update("volume", &R::IDENTITY);
         params.update("pitch", &R::EQUILIBRIUM);
         Self {
-            sounds: sounds::Sounds::default(),
+            chain: Chain::empty(),
+            garbage: chain::garbage(),
             audio_tx,
             audio_rx,
             params,
-            dasp_test: dasp_test::DaspTestData::default(),
-            general: general::General::default()
+            recorder: None,
+            input: None
         }
     }
 }
 
+impl AudioData<f32> {
+    pub fn new(cfg: &Config) -> Self {
+        let mut data = Self::default();
+        data.chain = Chain::new(cfg);
+        data
+    }
+
+    /// Whether the chain still has voices sounding.
+    pub fn is_active(&self) -> bool {
+        self.chain.is_active()
+    }
+}
+
 pub struct Audio {
     // host: VSTHost,
     host_buffer: HostBuffer<f32>,
     // host: cpal::Host,
     // output: cpal::Device,
     stream: nannou_audio::Stream<AudioData<f32>>,
+    input_stream: Option<nannou_audio::Stream<input::Capture>>,
     pub(crate) audio_tx: Sender<AudioMessage>,
     pub(crate) audio_rx: Receiver<AudioMessage>,
 }
 unsafe impl Send for Audio {}
 
-impl Default for Audio {
-    fn default() -> Self {
+impl Audio {
+    /// Open the output at the configured rate, or at the device's own if it
+    /// doesn't take it. Nothing plays until `start`.
+    pub fn new(cfg: &Config) -> Self {
         // let (audio_tx, audio_rx) = unbounded();
         // let mut host = VSTHost::load("Upright Piano.vst");
         let host_buffer: HostBuffer<f32> = HostBuffer::new(2, 2);
@@ -62,42 +103,116 @@ impl Default for Audio {
         let data = AudioData::default();
         let audio_tx = data.audio_tx.clone();
         let audio_rx = data.audio_rx.clone();
-        let audio_stream = audio_host
+        let audio_stream = match audio_host
             .new_output_stream(data)
             .render(crate::audio::audio)
-            .build()
-            .unwrap();
+            .sample_rate(cfg.audio.sample_rate)
+            .channels(cfg.audio.channels)
+            .build() {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Unable to open the audio output at {}Hz: {:?}", cfg.audio.sample_rate, e);
+                let data = AudioData { audio_tx: audio_tx.clone(), audio_rx: audio_rx.clone(), ..AudioData::default() };
+                audio_host
+                    .new_output_stream(data)
+                    .render(crate::audio::audio)
+                    .channels(cfg.audio.channels)
+                    .build()
+                    .unwrap()
+            }
+        };
 
         Self { 
             host_buffer,
             audio_rx,
             audio_tx,
-            stream: audio_stream
+            stream: audio_stream,
+            input_stream: None
+        }
+    }
+
+    /// The rate the output runs at, the config passed to `start` should match it.
+    pub fn sample_rate(&self) -> u32 {
+        self.stream.cpal_config().sample_rate.0
+    }
+
+    /// Hand the chain, the recorder and the audio input to the output stream.
+    pub fn start(&mut self, cfg: &Config) {
+        let chain = Chain::new(cfg);
+        let recorder = record::Recorder::new(cfg);
+        let input = input::open(&nannou_audio::Host::new(), cfg).map(|(stream, input)| {
+            self.input_stream = Some(stream);
+            input
+        });
+        // the empty chain and missing recorder it replaces free nothing
+        let sent = self.stream.send(move |data| {
+            data.chain = chain;
+            data.recorder = Some(recorder);
+            data.input = input;
+        });
+        if sent.is_err() {
+            println!("Unable to start the audio output");
         }
     }
 }
 pub enum AudioMessage {
-    SoundOn { id: u64, sound: audrey::read::BufFileReader },
-    SoundOff { id: u64 },
-    SignalUpdate { key: String, value: f32 }
+    /// `playback` is taken from the config entry the sample was decoded from,
+    /// `velocity` is the gain after the velocity curve, between 0 and 1,
+    /// `rate` is the playback speed, 2.0 plays an octave up
+    SoundOn { key: VoiceKey, sample: Sample, playback: Playback, velocity: f32, rate: f64 },
+    SoundOff { key: VoiceKey },
+    /// a synthesized drum, `velocity` is the gain after the velocity curve
+    DrumHit { drum: Drum, velocity: f32 },
+    SignalUpdate { key: String, value: f32 },
+    /// start or stop recording the master output
+    RecordToggle,
+    /// capture the audio input until the SampleStop for the same key
+    SampleStart { take: Take },
+    SampleStop { key: String },
+    /// notes for a synth, fm or pluck processor, `synth` is where it is in the processors
+    /// of the config, see `Config::processor_index`, `velocity` is the gain after the velocity curve
+    NoteOn { synth: usize, key: VoiceKey, velocity: f32 },
+    NoteOff { synth: usize, key: VoiceKey },
+    /// `bend` is between -1 and 1
+    PitchBend { synth: usize, channel: u8, bend: f32 },
+    /// a chain built from a new config, it takes over the processors it shares with the old one
+    ChainUpdate(Chain)
+}
+impl AudioMessage {
+    /// The processor a note or bend is for.
+    pub fn synth(&self) -> Option<usize> {
+        match self {
+            AudioMessage::NoteOn { synth, .. } | AudioMessage::NoteOff { synth, .. } | AudioMessage::PitchBend { synth, .. } => Some(*synth),
+            _ => None
+        }
+    }
 }
 
 // A function that renders the given `Audio` to the given `Buffer`.
 // In this case we play the audio file.
 pub fn audio(data: &mut AudioData<f32>, buffer: &mut nannou_audio::Buffer) {
+    let channels = buffer.channels();
+    let sample_rate = buffer.sample_rate();
+    render(data, &mut Block::new(buffer, channels, sample_rate));
+}
+
+/// Run the processing chain over one block, the output stream and the offline
+/// renderer both go through here.
+pub fn render(data: &mut AudioData<f32>, buffer: &mut Block) {
     // process messages
     let messages = pull_messages(data);
     process_messages(data, messages);
     // dsp::slice::equilibrium(buffer);
-    data.sounds.param("A", 1.0);
-    data.sounds.process(buffer);
+    // live input goes through the same chain as the samples
+    if let Some(input) = data.input.as_mut() {
+        input.process(buffer);
+    }
 
-    data.dasp_test.param("A", 1.0);
-    data.dasp_test.param("R", data.params.get("pitch"));
-    data.dasp_test.process(buffer);
+    data.chain.process(buffer, &data.params, &data.garbage);
 
-    data.general.param("A", data.params.get("volume"));
-    data.general.process(buffer);
+    if let Some(recorder) = data.recorder.as_mut() {
+        recorder.process(buffer);
+    }
 }
 
 fn pull_messages(data: &AudioData<f32>) -> Vec<AudioMessage> {
@@ -107,29 +222,61 @@ fn pull_messages(data: &AudioData<f32>) -> Vec<AudioMessage> {
 fn process_messages(data: &mut AudioData<f32>, messages: Vec<AudioMessage>) {
     for m in messages {
         match m {
-            AudioMessage::SoundOn { id, sound } => {
-                data.sounds.on(id, sound);
-            }
-            AudioMessage::SoundOff { id } => {
-                data.sounds.off(id);
+            AudioMessage::ChainUpdate(mut chain) => {
+                chain.adopt(std::mem::replace(&mut data.chain, Chain::empty()), &data.garbage);
+                data.chain = chain;
             }
             AudioMessage::SignalUpdate { key, value } => {
+                if key == RECORD_SIGNAL {
+                    if let Some(recorder) = data.recorder.as_mut() {
+                        recorder.set(value > 0.5);
+                    }
+                }
                 data.params.update(&key, &value);
             }
-            _ => ()
+            AudioMessage::SampleStart { take } => {
+                // without an input the take goes straight back, empty
+                match data.input.as_mut() {
+                    Some(input) => input.start(take),
+                    None => take.finish()
+                }
+            }
+            AudioMessage::SampleStop { key } => {
+                if let Some(input) = data.input.as_mut() {
+                    input.stop(&key);
+                }
+            }
+            AudioMessage::RecordToggle => {
+                if let Some(recorder) = data.recorder.as_mut() {
+                    recorder.toggle();
+                }
+            }
+            m => data.chain.message(m)
         }
     };
 }
 
-pub fn launch_sound(cfg: &Arc<Config>, audio_tx: Sender<AudioMessage>, name: &str, on: bool) {
+/// Playback rate that repitches a sample recorded at `root` to `note`.
+pub fn note_rate(note: u8, root: u8) -> f64 {
+    2f64.powf((note as f64 - root as f64) / 12.0)
+}
+
+pub fn launch_sound(cfg: &Arc<Config>, audio_tx: Sender<AudioMessage>, name: &str, key: VoiceKey, on: bool) {
     if on {
-        let maybe_sound = cfg.sounds.get(&name.to_string(), 0);
+        if let Some(drum) = Drum::from_key(name) {
+            println!("Play {}", name);
+            audio_tx.send(AudioMessage::DrumHit { drum, velocity: 1.0 }).unwrap();
+            return;
+        }
+        // keys on the computer keyboard always play middle C at full velocity
+        let maybe_sound = cfg.sounds.select(name, 60, 127);
         if let Some(sound) = maybe_sound {
-            println!("Load {}, {}", name, sound.path);
-            let r_sound = audrey::open(&sound.path);
-            if let Ok(s) = r_sound {
-                println!("Play {}", name);
-                audio_tx.send(AudioMessage::SoundOn {id: 0, sound: s}).unwrap();
+            if let Some(s) = cfg.pool.get(&sound.path) {
+                println!("Play {}, {}", name, sound.path);
+                let velocity = sound.velocity.unwrap_or_default().gain(127);
+                let rate = sound.root.map_or(1.0, |root| note_rate(60, root));
+                let playback = Playback::new(&sound, &s);
+                audio_tx.send(AudioMessage::SoundOn {key, sample: s, playback, velocity, rate}).unwrap();
             } else {
                 println!("Unable to load sound {}", &sound.path);
             }
@@ -138,8 +285,19 @@ pub fn launch_sound(cfg: &Arc<Config>, audio_tx: Sender<AudioMessage>, name: &st
         }
     } else {
         println!("Stop {}", name);
-        audio_tx.send(AudioMessage::SoundOff {id: 0}).unwrap();
+        audio_tx.send(AudioMessage::SoundOff {key}).unwrap();
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn note_rates() {
+        assert_eq!(note_rate(60, 60), 1.0);
+        assert!((note_rate(72, 60) - 2.0).abs() < 1e-9);
+        assert!((note_rate(48, 60) - 0.5).abs() < 1e-9);
+        assert!((note_rate(67, 60) - 1.4983).abs() < 1e-4);
+    }
+}
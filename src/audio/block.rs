@@ -0,0 +1,41 @@
+use std::ops::{Deref, DerefMut};
+
+/// Interleaved frames the processors render into, borrowed either from the
+/// output stream or from the offline renderer.
+pub struct Block<'a> {
+    samples: &'a mut [f32],
+    channels: usize,
+    sample_rate: u32
+}
+impl<'a> Block<'a> {
+    pub fn new(samples: &'a mut [f32], channels: usize, sample_rate: u32) -> Self {
+        Self { samples, channels, sample_rate }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn len_frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    pub fn frames_mut(&mut self) -> std::slice::ChunksMut<'_, f32> {
+        self.samples.chunks_mut(self.channels)
+    }
+}
+impl<'a> Deref for Block<'a> {
+    type Target = [f32];
+    fn deref(&self) -> &[f32] {
+        self.samples
+    }
+}
+impl<'a> DerefMut for Block<'a> {
+    fn deref_mut(&mut self) -> &mut [f32] {
+        self.samples
+    }
+}
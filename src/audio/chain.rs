@@ -0,0 +1,275 @@
+use crossbeam::channel::{Sender, bounded};
+use std::sync::Arc;
+
+use crate::audio::{Block, Sample};
+use crate::audio::parameters::Parameters;
+use crate::audio::processor::{self, Processor};
+use crate::audio::AudioMessage;
+use crate::config::{Config, ParamValue, Patch};
+
+/// A param of a processor and what sets it, kept within the range of the param.
+struct Binding {
+    key: String,
+    value: ParamValue,
+    min: f32,
+    max: f32
+}
+
+/// A replaced processor still playing while it fades out under the new one.
+struct Fade {
+    processor: Box<dyn Processor>,
+    remaining: usize,
+    length: usize
+}
+
+struct ChainEntry {
+    // where its config is in the processors of the config, notes are sent by this
+    index: usize,
+    name: String,
+    kind: String,
+    bypass: bool,
+    params: Vec<Binding>,
+    patch: Option<Patch>,
+    sound: Option<String>,
+    // what the sound resolved to, it changes when the sound is sampled again
+    sample: Option<Sample>,
+    processor: Box<dyn Processor>,
+    // oldest first, room for MAX_FADES is kept so adopting never allocates
+    fades: Vec<Fade>
+}
+
+/// What the audio thread is done with, freed on the thread started by `garbage`.
+// only ever dropped, never read
+#[allow(dead_code)]
+pub enum Garbage {
+    Processor(Box<dyn Processor>),
+    Chain(Chain),
+    /// the sample of a voice that ended, maybe the last one holding it
+    Sample(Sample)
+}
+
+/// Start a thread dropping what the audio thread hands it, so the audio
+/// thread never frees memory itself.
+pub fn garbage() -> Sender<Garbage> {
+    // bounded so sending from the audio thread never allocates
+    let (tx, rx) = bounded(GARBAGE_CAPACITY);
+    std::thread::spawn(move || rx.iter().for_each(drop));
+    tx
+}
+
+/// Hand `garbage` to the drop thread, if it falls behind it is freed here after all.
+pub fn discard(tx: &Sender<Garbage>, garbage: Garbage) {
+    tx.try_send(garbage).ok();
+}
+
+/// Left in place of a processor moved out on the audio thread, boxing it doesn't allocate.
+struct Taken;
+impl Processor for Taken {
+    fn param(&mut self, _key: &str, _value: f32) {}
+    fn process(&mut self, _buffer: &mut Block) {}
+}
+
+/// Set the params of a processor from their bindings, then hand it the signals
+/// and the drop thread.
+fn bind(params: &[Binding], processor: &mut Box<dyn Processor>, signals: &Parameters<f32>, garbage: &Sender<Garbage>) {
+    for b in params.iter() {
+        let v = match &b.value {
+            ParamValue::Value(v) => *v,
+            ParamValue::Signal(signal) => signals.get(signal)
+        };
+        processor.param(&b.key, v.max(b.min).min(b.max));
+    }
+    processor.signals(signals);
+    processor.garbage(garbage);
+}
+
+fn same_sample(a: &Option<Sample>, b: &Option<Sample>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(&a.data, &b.data),
+        (None, None) => true,
+        _ => false
+    }
+}
+
+/// Seconds a replaced processor takes to fade out under its replacement.
+const CROSSFADE_TIME: f32 = 0.05;
+/// Block size new chains are prepared for before they reach the audio thread.
+const PREPARED_BLOCK: usize = 2048;
+/// Replaced processors fading out at once in one entry, the oldest is cut past this.
+const MAX_FADES: usize = 4;
+/// Things waiting to be dropped before the audio thread frees them itself.
+const GARBAGE_CAPACITY: usize = 64;
+
+/// Processors run in order over each block, built from the `[[processor]]`
+/// entries of run.toml.
+pub struct Chain {
+    entries: Vec<ChainEntry>,
+    // format the processors were last prepared for, the block size is the largest seen
+    sample_rate: u32,
+    block_size: usize,
+    // the input of the fading processors, and the output of each in turn
+    input: Vec<f32>,
+    scratch: Vec<f32>
+}
+impl Chain {
+    pub fn new(config: &Config) -> Self {
+        let mut entries = vec![];
+        for (index, cfg) in config.processors.iter().enumerate() {
+            let patch = cfg.patch.as_ref().and_then(|path| config.patches.get(path));
+            let mut processor = match processor::create(cfg, config) {
+                Some(p) => p,
+                None => {
+                    println!("Unknown processor type {}", cfg.kind);
+                    continue;
+                }
+            };
+            for d in processor.params() {
+                processor.param(d.name, d.default);
+            }
+            let mut params = vec![];
+            for (key, value) in cfg.params.iter() {
+                match processor.params().iter().find(|d| d.name == key) {
+                    Some(d) => params.push(Binding { key: key.clone(), value: value.clone(), min: d.min, max: d.max }),
+                    None => {
+                        let known: Vec<String> = processor.params().iter().map(|d| format!("{} ({})", d.name, d.description)).collect();
+                        println!("Processor {} has no param {}, it has {}", cfg.name(), key, known.join(", "));
+                    }
+                }
+            }
+            entries.push(ChainEntry {
+                index,
+                name: cfg.name().to_string(),
+                kind: cfg.kind.clone(),
+                bypass: cfg.bypass,
+                params,
+                patch: patch.cloned(),
+                sound: cfg.sound.clone(),
+                sample: processor::sample(cfg, config),
+                processor,
+                fades: Vec::with_capacity(MAX_FADES)
+            });
+        }
+        // prepared here so a chain sent to the audio thread is ready to run
+        for entry in entries.iter_mut() {
+            entry.processor.prepare(config.audio.sample_rate, PREPARED_BLOCK);
+        }
+        let input = Vec::with_capacity(PREPARED_BLOCK * config.audio.channels);
+        let scratch = Vec::with_capacity(PREPARED_BLOCK * config.audio.channels);
+        Self { entries, sample_rate: config.audio.sample_rate, block_size: PREPARED_BLOCK, input, scratch }
+    }
+
+    /// A chain that does nothing.
+    pub fn empty() -> Self {
+        Self { entries: vec![], sample_rate: 0, block_size: 0, input: vec![], scratch: vec![] }
+    }
+
+    /// Take over the processors of `old` that have the same name, type, patch
+    /// and sound, so a config change keeps playing voices and oscillator phases.
+    /// When only the patch or sound differs the old one fades out under the new one.
+    /// What is left of `old` goes to the drop thread.
+    pub fn adopt(&mut self, mut old: Chain, garbage: &Sender<Garbage>) {
+        let length = (CROSSFADE_TIME * old.sample_rate as f32) as usize;
+        // old entries not matched yet are kept at the front
+        let mut unmatched = old.entries.len();
+        for entry in self.entries.iter_mut() {
+            let i = match old.entries[..unmatched].iter().position(|o| o.name == entry.name && o.kind == entry.kind) {
+                Some(i) => i,
+                None => continue
+            };
+            unmatched -= 1;
+            old.entries.swap(i, unmatched);
+            let o = &mut old.entries[unmatched];
+            // fades still running carry on, they fit as both have room for MAX_FADES
+            entry.fades.extend(o.fades.drain(..));
+            if o.patch != entry.patch || o.sound != entry.sound {
+                if !o.bypass && length > 0 {
+                    if entry.fades.len() >= MAX_FADES {
+                        discard(garbage, Garbage::Processor(entry.fades.remove(0).processor));
+                    }
+                    let processor = std::mem::replace(&mut o.processor, Box::new(Taken));
+                    entry.fades.push(Fade { processor, remaining: length, length });
+                }
+                continue;
+            }
+            // the new processor is dropped with the rest of the old chain
+            std::mem::swap(&mut entry.processor, &mut o.processor);
+            // coming out of bypass, it starts clean
+            if o.bypass && !entry.bypass {
+                entry.processor.reset();
+            }
+            // the sound was decoded or sampled again, the old sample goes with the old chain
+            if !same_sample(&o.sample, &entry.sample) {
+                o.sample = entry.processor.replace_sample(entry.sample.clone());
+            }
+        }
+        discard(garbage, Garbage::Chain(old));
+    }
+
+    /// Whether a processor is still sounding or fading out.
+    pub fn is_active(&self) -> bool {
+        self.entries.iter().filter(|e| !e.bypass).any(|e| e.processor.is_active() || !e.fades.is_empty())
+    }
+
+    /// Offer the message to each processor in order until one uses it, notes
+    /// only go to the processor they are for.
+    pub fn message(&mut self, message: AudioMessage) {
+        if let Some(synth) = message.synth() {
+            if let Some(entry) = self.entries.iter_mut().find(|e| e.index == synth && !e.bypass) {
+                entry.processor.message(message);
+            }
+            return;
+        }
+        let mut message = Some(message);
+        for entry in self.entries.iter_mut().filter(|e| !e.bypass) {
+            message = match message {
+                Some(m) => entry.processor.message(m),
+                None => return
+            };
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut Block, signals: &Parameters<f32>, garbage: &Sender<Garbage>) {
+        let channels = buffer.channels();
+        let sample_rate = buffer.sample_rate();
+        if sample_rate != self.sample_rate || buffer.len_frames() > self.block_size {
+            self.sample_rate = sample_rate;
+            self.block_size = buffer.len_frames();
+            for entry in self.entries.iter_mut() {
+                entry.processor.prepare(self.sample_rate, self.block_size);
+                for fade in entry.fades.iter_mut() {
+                    fade.processor.prepare(self.sample_rate, self.block_size);
+                }
+            }
+            // so copying a block in never reallocates
+            self.input.reserve(self.block_size * channels);
+            self.scratch.reserve(self.block_size * channels);
+        }
+        let Chain { entries, input, scratch, .. } = self;
+        for entry in entries.iter_mut().filter(|e| !e.bypass) {
+            bind(&entry.params, &mut entry.processor, signals, garbage);
+            if !entry.fades.is_empty() {
+                input.clear();
+                input.extend_from_slice(buffer);
+            }
+            entry.processor.process(buffer);
+
+            // the old processors each run on a copy of the input
+            for fade in entry.fades.iter_mut() {
+                bind(&entry.params, &mut fade.processor, signals, garbage);
+                scratch.clear();
+                scratch.extend_from_slice(input);
+                fade.processor.process(&mut Block::new(scratch, channels, sample_rate));
+                for (frame, old) in buffer.frames_mut().zip(scratch.chunks(channels)) {
+                    let g = fade.remaining as f32 / fade.length as f32;
+                    for (s, o) in frame.iter_mut().zip(old) {
+                        *s = *s * (1.0 - g) + *o * g;
+                    }
+                    fade.remaining = fade.remaining.saturating_sub(1);
+                }
+            }
+            while let Some(i) = entry.fades.iter().position(|f| f.remaining == 0) {
+                discard(garbage, Garbage::Processor(entry.fades.remove(i).processor));
+            }
+        }
+    }
+}
@@ -2,6 +2,13 @@ use dasp::slice::ToFrameSliceMut;
 use dsp::{Frame, FromSample, Graph, Node, Sample, Walker};
 use dsp::daggy::NodeIndex;
 use crate::audio::parameters::Parameters;
+use crate::audio::Block;
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::nodes;
+use crate::audio::fm;
+use crate::audio::wavetable;
+use crate::config::{Adsr, NodeKind, ParamValue, Patch};
+use std::collections::HashMap;
 
 const CHANNELS: usize = 2;
 const A5_HZ: Frequency = 440.0;
@@ -15,10 +22,18 @@ type Phase = f64;
 type Frequency = f64;
 type Volume = f32;
 
+/// A node param of a patch that follows a signal.
+struct NodeBinding {
+    node: NodeIndex<usize>,
+    param: &'static str,
+    signal: String
+}
+
 pub struct DaspTestData {
     params: Parameters<f32>,
     graph: Graph<[f32; 2], DspNode>,
     synth: NodeIndex<usize>,
+    bindings: Vec<NodeBinding>,
     buf: Vec<FrameType>
 }
 impl Default for DaspTestData {
@@ -40,27 +55,103 @@ impl Default for DaspTestData {
             params: Parameters::default(),
             graph,
             synth,
+            bindings: vec![],
             buf: Vec::with_capacity(2048)
         }
     }
 }
 impl DaspTestData {
-    pub fn param(&mut self, key: &str, value: f32) {
+    /// Build the graph declared in a patch file instead of the test oscillators.
+    pub fn from_patch(patch: &Patch) -> Self {
+        let mut graph = Graph::new();
+        let mut indices = HashMap::new();
+        let mut bindings = vec![];
+        for n in patch.node.iter() {
+            let (node, params) = DspNode::from_config(&n.kind);
+            let idx = graph.add_node(node);
+            for (param, value) in params {
+                match value {
+                    ParamValue::Value(v) => graph[idx].set(param, v),
+                    ParamValue::Signal(signal) => bindings.push(NodeBinding { node: idx, param, signal })
+                }
+            }
+            if indices.insert(n.name.clone(), idx).is_some() {
+                println!("Patch node {} is declared twice, connections go to the last", n.name);
+            }
+        }
+        for c in patch.connection.iter() {
+            match (indices.get(&c.from), indices.get(&c.to)) {
+                (Some(from), Some(to)) => {
+                    if let Err(err) = graph.add_connection(*from, *to) {
+                        println!("Patch connection {} -> {}: {}", c.from, c.to, err);
+                    }
+                }
+                _ => println!("Patch connection {} -> {} names an unknown node", c.from, c.to)
+            }
+        }
+        let output = match indices.get(&patch.output) {
+            Some(idx) => *idx,
+            None => {
+                println!("Patch output {} is not a node", patch.output);
+                // silent
+                graph.add_node(DspNode::Synth)
+            }
+        };
+        graph.set_master(Some(output));
+        println!("Loaded patch with {} nodes", graph.node_count());
+
+        Self {
+            params: Parameters::default(),
+            graph,
+            synth: output,
+            bindings,
+            buf: Vec::with_capacity(2048)
+        }
+    }
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of the oscillators", 0.0, 1.0, 1.0),
+    ParamDescriptor::new("R", "how fast the oscillators pitch down", -1.0, 1.0, 0.0)
+];
+
+impl Processor for DaspTestData {
+    fn prepare(&mut self, _sample_rate: u32, block_size: usize) {
+        if self.buf.len() < block_size {
+            self.buf.resize(block_size, FrameType::EQUILIBRIUM);
+        }
+    }
+
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
         self.params.update(key, &value);
     }
 
-    pub fn process(&mut self, buffer: &mut nannou_audio::Buffer) {
+    fn reset(&mut self) {
+        for i in 0..self.graph.node_count() {
+            if let Some(node) = self.graph.node_mut(NodeIndex::new(i)) {
+                node.reset();
+            }
+        }
+    }
+
+    fn signals(&mut self, signals: &Parameters<f32>) {
+        for b in self.bindings.iter() {
+            self.graph[b.node].set(b.param, signals.get(&b.signal));
+        }
+    }
+
+    fn process(&mut self, buffer: &mut Block) {
         let channels = buffer.channels();
         let sample_rate = buffer.sample_rate();
         let amp = self.params.get("A");
         let rate = self.params.get("R");
 
-        // make sure we have a buffer big enough
-        if self.buf.len() < buffer.len_frames() {
-            self.buf.resize(buffer.len_frames(), FrameType::EQUILIBRIUM);
-        }
-
-        let mut samples = self.buf.as_mut_slice();//into_boxed_slice();
+        // only as many frames as the block, blocks vary in size when rendering offline
+        let mut samples = &mut self.buf[..buffer.len_frames()];
         self.graph.audio_requested(&mut samples, sample_rate as f64);
         for (frame, graph_frame) in buffer.frames_mut().zip(samples.iter()) {
             for (sample, graph_sample) in frame.iter_mut().zip(graph_frame) {
@@ -69,8 +160,10 @@ impl DaspTestData {
         }
         self.post(buffer, rate);
     }
+}
 
-    fn post(&mut self, buffer: &mut nannou_audio::Buffer, pitch_control: f32) {
+impl DaspTestData {
+    fn post(&mut self, buffer: &mut Block, pitch_control: f32) {
         // Traverse inputs or outputs of a node with the following pattern.
         let mut inputs = self.graph.inputs(self.synth);
         while let Some(input_idx) = inputs.next_node(&self.graph) {
@@ -98,6 +191,75 @@ enum DspNode {
     /// Oscillator will be our generator type of node, meaning that we will override
     /// the way it provides audio via its `audio_requested` method.
     Oscillator(Phase, Frequency, Volume),
+    /// nodes declared in patch files
+    Wave(nodes::Oscillator),
+    Noise(nodes::Noise),
+    Wavetable(wavetable::Wavetable),
+    Filter(nodes::Filter),
+    Envelope(nodes::Gate),
+    Fm(fm::FmNode),
+    Mixer(nodes::Mixer)
+}
+impl DspNode {
+    /// The node for a patch entry and the params to set on it.
+    fn from_config(kind: &NodeKind) -> (DspNode, Vec<(&'static str, ParamValue)>) {
+        match kind {
+            NodeKind::Oscillator { wave, freq, amp, width } => {
+                (DspNode::Wave(nodes::Oscillator::new(*wave)), vec![("freq", freq.clone()), ("amp", amp.clone()), ("width", width.clone())])
+            }
+            NodeKind::Wavetable { path, frame_size, freq, amp, position } => {
+                // a sine keeps the patch playing when the file is missing
+                let tables = wavetable::Tables::load(path, *frame_size).unwrap_or_else(wavetable::Tables::sine);
+                (DspNode::Wavetable(wavetable::Wavetable::new(tables)), vec![("freq", freq.clone()), ("amp", amp.clone()), ("position", position.clone())])
+            }
+            NodeKind::Noise { amp } => {
+                (DspNode::Noise(nodes::Noise::new()), vec![("amp", amp.clone())])
+            }
+            NodeKind::Filter { mode, cutoff, q } => {
+                (DspNode::Filter(nodes::Filter::new(*mode)), vec![("cutoff", cutoff.clone()), ("q", q.clone())])
+            }
+            NodeKind::Envelope { gate, attack, hold, decay, sustain, release } => {
+                let adsr = Adsr { attack: *attack, hold: *hold, decay: *decay, sustain: *sustain, release: *release };
+                (DspNode::Envelope(nodes::Gate::new(adsr)), vec![("gate", gate.clone())])
+            }
+            NodeKind::Fm { gate, freq, algorithm, feedback, ratio, level, envelope } => {
+                let mut envelopes = [Adsr::default(); fm::OPERATORS];
+                envelopes.iter_mut().zip(envelope.iter()).for_each(|(e, c)| *e = *c);
+                let mut params = vec![("gate", gate.clone()), ("freq", freq.clone()), ("algorithm", algorithm.clone()), ("feedback", feedback.clone())];
+                params.extend(fm::RATIO.iter().cloned().zip(ratio.iter().cloned()));
+                params.extend(fm::LEVEL.iter().cloned().zip(level.iter().cloned()));
+                (DspNode::Fm(fm::FmNode::new(envelopes)), params)
+            }
+            NodeKind::Mixer { gain } => {
+                (DspNode::Mixer(nodes::Mixer::new()), vec![("gain", gain.clone())])
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            DspNode::Oscillator(phase, _, _) => *phase = 0.0,
+            DspNode::Wave(n) => n.reset(),
+            DspNode::Wavetable(n) => n.reset(),
+            DspNode::Filter(n) => n.reset(),
+            DspNode::Envelope(n) => n.reset(),
+            DspNode::Fm(n) => n.reset(),
+            _ => ()
+        }
+    }
+
+    fn set(&mut self, param: &str, value: f32) {
+        match self {
+            DspNode::Wave(n) => n.set(param, value),
+            DspNode::Noise(n) => n.set(param, value),
+            DspNode::Wavetable(n) => n.set(param, value),
+            DspNode::Filter(n) => n.set(param, value),
+            DspNode::Envelope(n) => n.set(param, value),
+            DspNode::Fm(n) => n.set(param, value),
+            DspNode::Mixer(n) => n.set(param, value),
+            _ => ()
+        }
+    }
 }
 impl Node<FrameType> for DspNode {
     /// Here we'll override the audio_requested method and generate a sine wave.
@@ -111,6 +273,13 @@ impl Node<FrameType> for DspNode {
                     Frame::from_fn(|_| val)
                 });
             }
+            DspNode::Wave(ref mut n) => n.process(buffer, sample_hz),
+            DspNode::Noise(ref mut n) => n.process(buffer),
+            DspNode::Wavetable(ref mut n) => n.process(buffer, sample_hz),
+            DspNode::Filter(ref mut n) => n.process(buffer, sample_hz),
+            DspNode::Envelope(ref mut n) => n.process(buffer, sample_hz),
+            DspNode::Fm(ref mut n) => n.process(buffer, sample_hz),
+            DspNode::Mixer(ref mut n) => n.process(buffer)
         }
     }
 }
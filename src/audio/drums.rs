@@ -0,0 +1,250 @@
+use crate::audio::parameters::Parameters;
+use crate::audio::{AudioMessage, Block, VoiceKey};
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::nodes::{self, Frame, Rng};
+use crate::audio::voices::{self, Voices};
+use crate::config::{Drum, FilterMode};
+
+/// Upper bound on drums ringing at once, the oldest hit is stolen past this.
+const MAX_VOICES: usize = 32;
+/// Frames each drum is worked out in, the filters run over them at once.
+const CHUNK: usize = 32;
+/// Level a hit is dropped below.
+const SILENCE: f32 = 0.0001;
+/// Square waves of the 808 cymbal and hat, in Hz.
+const METALLIC: [f64; 6] = [205.3, 304.4, 369.6, 522.7, 540.0, 800.0];
+/// Seconds a choked open hat takes to fade out.
+const CHOKE_TIME: f32 = 0.01;
+
+/// Level at `t` seconds into a fade of 60dB over `seconds`.
+fn fall(t: f32, seconds: f32) -> f32 {
+    (-6.9 * t / seconds.max(0.001)).exp()
+}
+
+struct Hit {
+    drum: Drum,
+    velocity: f32,
+    // frames since the hit
+    age: usize,
+    // what the params were when hit
+    pitch: f32,
+    sweep: f32,
+    decay: f32,
+    tone: f32,
+    phases: [f64; 6],
+    filter: Option<nodes::Filter>,
+    // the one pole lowpass the snare noise is highpassed with
+    low: f32,
+    // the age it was choked at
+    choked: Option<usize>,
+    level: f32,
+    done: bool
+}
+impl Hit {
+    fn new(drum: Drum, velocity: f32, params: &Parameters<f32>) -> Self {
+        let p = |key: &str| params.get(key);
+        let (pitch, sweep, decay, tone, filter) = match drum {
+            Drum::Kick => (p("kick_pitch"), p("kick_sweep"), p("kick_decay"), 0.0, None),
+            Drum::Snare => (p("snare_tone"), 0.5, p("snare_decay"), p("snare_snappy"), None),
+            Drum::Hat | Drum::OpenHat => {
+                let decay = if drum == Drum::Hat { p("hat_decay") } else { p("openhat_decay") };
+                let mut filter = nodes::Filter::new(FilterMode::Highpass);
+                filter.set("cutoff", 7000.0);
+                filter.set("q", 0.9);
+                (p("hat_tone"), 0.0, decay, 0.0, Some(filter))
+            }
+            Drum::Clap => {
+                let mut filter = nodes::Filter::new(FilterMode::Bandpass);
+                filter.set("cutoff", p("clap_tone"));
+                filter.set("q", 2.0);
+                (p("clap_tone"), 0.0, p("clap_decay"), 0.0, Some(filter))
+            }
+            Drum::Tom => (p("tom_pitch"), 0.3, p("tom_decay"), 0.0, None)
+        };
+        Self { drum, velocity, age: 0, pitch, sweep, decay, tone, phases: [0.0; 6], filter, low: 0.0, choked: None, level: 1.0, done: false }
+    }
+
+    /// One frame before the filter, `level` follows it down.
+    fn next(&mut self, sample_rate: f32, noise: f32) -> f32 {
+        let t = self.age as f32 / sample_rate;
+        let step = |phase: &mut f64, freq: f32| {
+            *phase = (*phase + freq as f64 / sample_rate as f64).fract();
+            (*phase * std::f64::consts::PI * 2.0).sin() as f32
+        };
+        self.level = fall(t, self.decay);
+        let v = match self.drum {
+            // a sine swept down from above its pitch, with a click
+            Drum::Kick | Drum::Tom => {
+                let freq = self.pitch * (1.0 + self.sweep * fall(t, 0.1));
+                let click = if self.drum == Drum::Kick { noise * fall(t, 0.004) * 0.3 } else { 0.0 };
+                step(&mut self.phases[0], freq) * self.level + click
+            }
+            // two detuned sines for the body, highpassed noise for the snares
+            Drum::Snare => {
+                let body = (step(&mut self.phases[0], self.pitch) + step(&mut self.phases[1], self.pitch * 1.83)) * 0.5 * fall(t, self.decay * 0.5);
+                self.low += (noise - self.low) * 0.2;
+                body * (1.0 - self.tone) + (noise - self.low) * self.level * self.tone
+            }
+            Drum::Hat | Drum::OpenHat => {
+                let mut sum = 0.0;
+                for (phase, freq) in self.phases.iter_mut().zip(METALLIC.iter()) {
+                    *phase = (*phase + freq * self.pitch as f64 / sample_rate as f64).fract();
+                    sum += if *phase < 0.5 { 1.0 } else { -1.0 };
+                }
+                sum / 6.0 * self.level
+            }
+            // three quick bursts of noise, then a tail
+            Drum::Clap => {
+                let burst = 0.01 * sample_rate;
+                let tail = 0.03 * sample_rate;
+                let age = self.age as f32;
+                self.level = if age < tail {
+                    fall((age % burst) / sample_rate, 0.01)
+                } else {
+                    fall((age - tail) / sample_rate, self.decay)
+                };
+                noise * self.level * 2.0
+            }
+        };
+        self.age += 1;
+        match self.choked {
+            Some(at) => {
+                let fade = fall((self.age - at) as f32 / sample_rate, CHOKE_TIME);
+                self.level = self.level.min(fade);
+                v * fade
+            }
+            None => v
+        }
+    }
+
+    fn choke(&mut self) {
+        if self.choked.is_none() {
+            self.choked = Some(self.age);
+        }
+    }
+
+    fn process(&mut self, buffer: &mut Block, gain: f32, random: &mut Rng) {
+        let sample_rate = buffer.sample_rate() as f32;
+        let sample_hz = sample_rate as f64;
+        let amp = gain * self.velocity;
+        let mut remaining = buffer.len_frames();
+        let mut frames = buffer.frames_mut();
+        let mut out: [Frame; CHUNK] = [[0.0; 2]; CHUNK];
+        while remaining > 0 && !self.done {
+            let n = remaining.min(CHUNK);
+            remaining -= n;
+            let out = &mut out[..n];
+            for o in out.iter_mut() {
+                let v = self.next(sample_rate, random.next());
+                *o = [v, v];
+            }
+            if let Some(filter) = self.filter.as_mut() {
+                filter.process(out, sample_hz);
+            }
+            for (frame, v) in (&mut frames).take(n).zip(out.iter()) {
+                for (sample, v) in frame.iter_mut().zip(v.iter()) {
+                    *sample += v * amp;
+                }
+            }
+            self.done = self.level < SILENCE;
+        }
+    }
+}
+
+impl voices::Voice for Hit {
+    fn key(&self) -> Option<VoiceKey> {
+        None
+    }
+
+    fn release(&mut self) {
+        self.choke();
+    }
+
+    fn is_released(&self) -> bool {
+        self.choked.is_some()
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn steal(&mut self) {
+        self.choke();
+    }
+}
+
+/// Kick, snare, hats, clap and tom, synthesized when a mapping plays a
+/// `drum:` sound. Params are taken when the drum is hit.
+pub struct Drums {
+    params: Parameters<f32>,
+    hits: Voices<Hit>,
+    // for the noise
+    random: Rng
+}
+impl Default for Drums {
+    fn default() -> Self {
+        Self { params: Parameters::default(), hits: Voices::new(MAX_VOICES), random: Rng::new(0x7f4a_7c15) }
+    }
+}
+impl Drums {
+    fn hit(&mut self, drum: Drum, velocity: f32) {
+        // a closed hat cuts off an open one
+        if drum == Drum::Hat {
+            self.hits.iter_mut().filter(|h| h.drum == Drum::OpenHat).for_each(|h| h.choke());
+        }
+        self.hits.start(Hit::new(drum, velocity, &self.params));
+    }
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of every drum", 0.0, 1.0, 0.8),
+    ParamDescriptor::new("kick_pitch", "Hz the kick settles at", 20.0, 200.0, 50.0),
+    ParamDescriptor::new("kick_sweep", "how far above its pitch the kick starts, times the pitch", 0.0, 10.0, 3.0),
+    ParamDescriptor::new("kick_decay", "seconds of the kick", 0.05, 3.0, 0.5),
+    ParamDescriptor::new("snare_tone", "Hz of the snare body", 80.0, 500.0, 180.0),
+    ParamDescriptor::new("snare_snappy", "noise against body of the snare", 0.0, 1.0, 0.6),
+    ParamDescriptor::new("snare_decay", "seconds of the snare", 0.05, 2.0, 0.25),
+    ParamDescriptor::new("hat_tone", "pitch of the hats, times the 808 ones", 0.25, 4.0, 1.0),
+    ParamDescriptor::new("hat_decay", "seconds of the closed hat", 0.01, 0.5, 0.06),
+    ParamDescriptor::new("openhat_decay", "seconds of the open hat", 0.1, 3.0, 0.6),
+    ParamDescriptor::new("clap_tone", "Hz of the clap", 300.0, 5000.0, 1200.0),
+    ParamDescriptor::new("clap_decay", "seconds of the clap tail", 0.05, 2.0, 0.3),
+    ParamDescriptor::new("tom_pitch", "Hz the tom settles at", 40.0, 500.0, 120.0),
+    ParamDescriptor::new("tom_decay", "seconds of the tom", 0.05, 3.0, 0.5)
+];
+
+impl Processor for Drums {
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
+        self.params.update(key, &value);
+    }
+
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        match message {
+            AudioMessage::DrumHit { drum, velocity } => {
+                self.hit(drum, velocity);
+                None
+            }
+            m => Some(m)
+        }
+    }
+
+    fn process(&mut self, buffer: &mut Block) {
+        let gain = self.params.get("A");
+        for hit in self.hits.iter_mut() {
+            hit.process(buffer, gain, &mut self.random);
+        }
+        self.hits.retain();
+    }
+
+    fn is_active(&self) -> bool {
+        !self.hits.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.hits.clear();
+    }
+}
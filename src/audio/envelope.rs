@@ -0,0 +1,89 @@
+use crate::config::Adsr;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+    Idle
+}
+
+/// Linear attack, hold, decay, sustain, release envelope stepped once per frame.
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    adsr: Adsr,
+    sample_rate: f32,
+    stage: Stage,
+    level: f32,
+    // frames spent in the hold stage
+    held: usize,
+    release_step: f32
+}
+impl Envelope {
+    pub fn new(adsr: Adsr, sample_rate: u32) -> Self {
+        Self { adsr, sample_rate: sample_rate as f32, stage: Stage::Attack, level: 0.0, held: 0, release_step: 0.0 }
+    }
+
+    /// Start fading out over the release time.
+    pub fn release(&mut self) {
+        self.release_in(self.adsr.release);
+    }
+
+    /// Start fading out from the current level over `seconds`.
+    pub fn release_in(&mut self, seconds: f32) {
+        if self.stage != Stage::Idle {
+            self.stage = Stage::Release;
+            self.release_step = self.level / self.frames(seconds);
+        }
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.stage == Stage::Release || self.stage == Stage::Idle
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.stage == Stage::Idle
+    }
+
+    pub fn next(&mut self) -> f32 {
+        match self.stage {
+            Stage::Attack => {
+                self.level += 1.0 / self.frames(self.adsr.attack);
+                if self.level >= 1.0 {
+                    self.level = 1.0;
+                    self.stage = Stage::Hold;
+                }
+            }
+            Stage::Hold => {
+                self.held += 1;
+                if self.held as f32 >= self.adsr.hold * self.sample_rate {
+                    self.stage = Stage::Decay;
+                }
+            }
+            Stage::Decay => {
+                self.level -= (1.0 - self.adsr.sustain) / self.frames(self.adsr.decay);
+                if self.level <= self.adsr.sustain {
+                    self.level = self.adsr.sustain;
+                    self.stage = Stage::Sustain;
+                }
+            }
+            Stage::Sustain => (),
+            Stage::Release => {
+                self.level -= self.release_step;
+                if self.level <= 0.0 {
+                    self.level = 0.0;
+                    self.stage = Stage::Idle;
+                }
+            }
+            Stage::Idle => ()
+        }
+        self.level
+    }
+
+    // at least one frame, so zero length stages end straight away
+    fn frames(&self, seconds: f32) -> f32 {
+        (seconds * self.sample_rate).max(1.0)
+    }
+}
@@ -0,0 +1,341 @@
+use std::f32::consts::PI;
+
+use crate::audio::parameters::Parameters;
+use crate::audio::{AudioMessage, Block, VoiceKey};
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::envelope::Envelope;
+use crate::audio::nodes::Frame;
+use crate::audio::voices::{self, Note, Voices};
+use crate::config::Adsr;
+
+/// Upper bound on simultaneous FM voices, the oldest voice is stolen past this.
+const MAX_VOICES: usize = 16;
+pub const OPERATORS: usize = 4;
+/// Param names of each operator, counting from 1 like the algorithms do.
+pub const RATIO: [&str; OPERATORS] = ["ratio1", "ratio2", "ratio3", "ratio4"];
+pub const LEVEL: [&str; OPERATORS] = ["level1", "level2", "level3", "level4"];
+const ATTACK: [&str; OPERATORS] = ["attack1", "attack2", "attack3", "attack4"];
+const DECAY: [&str; OPERATORS] = ["decay1", "decay2", "decay3", "decay4"];
+const SUSTAIN: [&str; OPERATORS] = ["sustain1", "sustain2", "sustain3", "sustain4"];
+const RELEASE: [&str; OPERATORS] = ["release1", "release2", "release3", "release4"];
+
+/// The eight algorithms of the four operator Yamaha chips: for each operator
+/// the ones modulating it, as bits, and which operators are heard.
+/// Operators only modulate higher ones, so they are worked out in order.
+const ALGORITHMS: [([u8; OPERATORS], u8); 8] = [
+    // 1 > 2 > 3 > 4
+    ([0, 0b0001, 0b0010, 0b0100], 0b1000),
+    // (1 + 2) > 3 > 4
+    ([0, 0, 0b0011, 0b0100], 0b1000),
+    // (1 + (2 > 3)) > 4
+    ([0, 0, 0b0010, 0b0101], 0b1000),
+    // ((1 > 2) + 3) > 4
+    ([0, 0b0001, 0, 0b0110], 0b1000),
+    // 1 > 2, 3 > 4
+    ([0, 0b0001, 0, 0b0100], 0b1010),
+    // 1 > 2, 3 and 4
+    ([0, 0b0001, 0b0001, 0b0001], 0b1110),
+    // 1 > 2, 3, 4
+    ([0, 0b0001, 0, 0], 0b1110),
+    // 1, 2, 3, 4
+    ([0, 0, 0, 0], 0b1111)
+];
+
+#[derive(Debug)]
+struct Operator {
+    phase: f32,
+    ratio: f32,
+    level: f32,
+    envelope: Option<Envelope>,
+    out: f32
+}
+impl Operator {
+    fn new() -> Self {
+        Self { phase: 0.0, ratio: 1.0, level: 0.0, envelope: None, out: 0.0 }
+    }
+}
+
+/// Four sine operators phase modulating each other by one of the algorithms,
+/// operator 1 can also modulate itself.
+#[derive(Debug)]
+pub struct FmVoice {
+    operators: [Operator; OPERATORS],
+    algorithm: usize,
+    feedback: f32,
+    freq: f32,
+    // the last two outputs of operator 1, averaged for the feedback
+    history: [f32; 2]
+}
+impl FmVoice {
+    pub fn new() -> Self {
+        Self {
+            operators: [Operator::new(), Operator::new(), Operator::new(), Operator::new()],
+            algorithm: 0,
+            feedback: 0.0,
+            freq: 440.0,
+            history: [0.0; 2]
+        }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        match param {
+            "freq" => self.freq = value,
+            "algorithm" => self.algorithm = (value.round().max(1.0) as usize - 1).min(ALGORITHMS.len() - 1),
+            "feedback" => self.feedback = value,
+            _ => {
+                if let Some(i) = RATIO.iter().position(|p| *p == param) {
+                    self.operators[i].ratio = value;
+                } else if let Some(i) = LEVEL.iter().position(|p| *p == param) {
+                    self.operators[i].level = value;
+                }
+            }
+        }
+    }
+
+    /// Start every operator envelope from the beginning.
+    pub fn start(&mut self, envelopes: &[Adsr; OPERATORS], sample_rate: u32) {
+        for (op, adsr) in self.operators.iter_mut().zip(envelopes.iter()) {
+            op.envelope = Some(Envelope::new(*adsr, sample_rate));
+        }
+    }
+
+    pub fn release(&mut self) {
+        for op in self.operators.iter_mut() {
+            if let Some(e) = op.envelope.as_mut() {
+                e.release();
+            }
+        }
+    }
+
+    /// Fade every operator out over `seconds` rather than its release.
+    pub fn release_in(&mut self, seconds: f32) {
+        for op in self.operators.iter_mut() {
+            if let Some(e) = op.envelope.as_mut() {
+                e.release_in(seconds);
+            }
+        }
+    }
+
+    pub fn is_released(&self) -> bool {
+        self.operators.iter().all(|op| op.envelope.as_ref().map_or(true, |e| e.is_released()))
+    }
+
+    /// Done once every operator that is heard has faded out.
+    pub fn is_idle(&self) -> bool {
+        let carriers = ALGORITHMS[self.algorithm].1;
+        self.operators.iter().enumerate()
+            .filter(|(i, _)| carriers & (1 << i) != 0)
+            .all(|(_, op)| op.envelope.as_ref().map_or(true, |e| e.is_idle()))
+    }
+
+    pub fn reset(&mut self) {
+        for op in self.operators.iter_mut() {
+            op.phase = 0.0;
+            op.envelope = None;
+            op.out = 0.0;
+        }
+        self.history = [0.0; 2];
+    }
+
+    pub fn next(&mut self, sample_rate: f32) -> f32 {
+        let (modulators, carriers) = ALGORITHMS[self.algorithm];
+        let mut heard = 0.0;
+        for i in 0..OPERATORS {
+            let mut modulation = 0.0;
+            for (j, m) in self.operators[..i].iter().enumerate() {
+                if modulators[i] & (1 << j) != 0 {
+                    modulation += m.out;
+                }
+            }
+            if i == 0 {
+                modulation += (self.history[0] + self.history[1]) * 0.5 * self.feedback;
+            }
+            let op = &mut self.operators[i];
+            let amp = match op.envelope.as_mut() {
+                Some(e) => e.next(),
+                None => 0.0
+            };
+            // a modulator at full level shifts the phase by up to a whole cycle
+            op.out = ((op.phase + modulation) * 2.0 * PI).sin() * op.level * amp;
+            op.phase = (op.phase + self.freq * op.ratio / sample_rate).fract();
+            if carriers & (1 << i) != 0 {
+                heard += op.out;
+            }
+        }
+        self.history = [self.operators[0].out, self.history[0]];
+        heard / carriers.count_ones() as f32
+    }
+}
+
+/// A patch node playing one FM voice, started when the gate goes above 0.5
+/// and released when it falls back.
+#[derive(Debug)]
+pub struct FmNode {
+    voice: FmVoice,
+    envelopes: [Adsr; OPERATORS],
+    gate: f32
+}
+impl FmNode {
+    pub fn new(envelopes: [Adsr; OPERATORS]) -> Self {
+        Self { voice: FmVoice::new(), envelopes, gate: 0.0 }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        match param {
+            "gate" => self.gate = value,
+            _ => self.voice.set(param, value)
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.voice.reset();
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame], sample_hz: f64) {
+        let open = !self.voice.is_released();
+        if self.gate > 0.5 && !open {
+            self.voice.start(&self.envelopes, sample_hz as u32);
+        } else if self.gate <= 0.5 && open {
+            self.voice.release();
+        }
+        for frame in buffer.iter_mut() {
+            let v = self.voice.next(sample_hz as f32);
+            *frame = [v, v];
+        }
+    }
+}
+
+struct Voice {
+    key: VoiceKey,
+    velocity: f32,
+    fm: FmVoice
+}
+impl voices::Voice for Voice {
+    fn key(&self) -> Option<VoiceKey> {
+        Some(self.key)
+    }
+
+    fn release(&mut self) {
+        self.fm.release();
+    }
+
+    fn is_released(&self) -> bool {
+        self.fm.is_released()
+    }
+
+    fn is_done(&self) -> bool {
+        self.fm.is_idle()
+    }
+
+    fn steal(&mut self) {
+        self.fm.release_in(voices::STEAL_TIME);
+    }
+}
+
+/// A polyphonic four operator FM synth, played like the subtractive one from
+/// devices mapped to it by name.
+pub struct Fm {
+    params: Parameters<f32>,
+    sample_rate: u32,
+    voices: Voices<Voice>
+}
+impl Default for Fm {
+    fn default() -> Self {
+        Self { params: Parameters::default(), sample_rate: 44100, voices: Voices::new(MAX_VOICES) }
+    }
+}
+impl Fm {
+    fn on(&mut self, key: VoiceKey, velocity: f32) {
+        self.voices.release(&key);
+        let p = &self.params;
+        let mut envelopes = [Adsr::default(); OPERATORS];
+        for (i, e) in envelopes.iter_mut().enumerate() {
+            *e = Adsr { attack: p.get(ATTACK[i]), hold: 0.0, decay: p.get(DECAY[i]), sustain: p.get(SUSTAIN[i]), release: p.get(RELEASE[i]) };
+        }
+        let mut fm = FmVoice::new();
+        fm.start(&envelopes, self.sample_rate);
+        self.voices.start(Voice { key, velocity, fm });
+    }
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of every voice", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("algorithm", "how the operators modulate each other, 1 to 8", 1.0, 8.0, 1.0),
+    ParamDescriptor::new("feedback", "how much operator 1 modulates itself", 0.0, 1.0, 0.0),
+    ParamDescriptor::new("bend", "semitones of a full pitch bend", 0.0, 24.0, 2.0),
+    ParamDescriptor::new("ratio1", "frequency of operator 1 relative to the note", 0.0, 32.0, 1.0),
+    ParamDescriptor::new("ratio2", "frequency of operator 2 relative to the note", 0.0, 32.0, 1.0),
+    ParamDescriptor::new("ratio3", "frequency of operator 3 relative to the note", 0.0, 32.0, 1.0),
+    ParamDescriptor::new("ratio4", "frequency of operator 4 relative to the note", 0.0, 32.0, 1.0),
+    ParamDescriptor::new("level1", "output of operator 1", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("level2", "output of operator 2", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("level3", "output of operator 3", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("level4", "output of operator 4", 0.0, 1.0, 1.0),
+    ParamDescriptor::new("attack1", "seconds of the operator 1 attack", 0.0, 10.0, 0.005),
+    ParamDescriptor::new("attack2", "seconds of the operator 2 attack", 0.0, 10.0, 0.005),
+    ParamDescriptor::new("attack3", "seconds of the operator 3 attack", 0.0, 10.0, 0.005),
+    ParamDescriptor::new("attack4", "seconds of the operator 4 attack", 0.0, 10.0, 0.005),
+    ParamDescriptor::new("decay1", "seconds of the operator 1 decay", 0.0, 10.0, 0.5),
+    ParamDescriptor::new("decay2", "seconds of the operator 2 decay", 0.0, 10.0, 0.5),
+    ParamDescriptor::new("decay3", "seconds of the operator 3 decay", 0.0, 10.0, 0.5),
+    ParamDescriptor::new("decay4", "seconds of the operator 4 decay", 0.0, 10.0, 0.5),
+    ParamDescriptor::new("sustain1", "level of the operator 1 sustain", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("sustain2", "level of the operator 2 sustain", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("sustain3", "level of the operator 3 sustain", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("sustain4", "level of the operator 4 sustain", 0.0, 1.0, 0.8),
+    ParamDescriptor::new("release1", "seconds of the operator 1 release", 0.0, 10.0, 0.3),
+    ParamDescriptor::new("release2", "seconds of the operator 2 release", 0.0, 10.0, 0.3),
+    ParamDescriptor::new("release3", "seconds of the operator 3 release", 0.0, 10.0, 0.3),
+    ParamDescriptor::new("release4", "seconds of the operator 4 release", 0.0, 10.0, 0.3)
+];
+
+impl Processor for Fm {
+    fn prepare(&mut self, sample_rate: u32, _block_size: usize) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
+        self.params.update(key, &value);
+    }
+
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        match self.voices.route(message) {
+            Note::On { key, velocity } => self.on(key, velocity),
+            Note::Off { key } => self.voices.release(&key),
+            Note::Bend => (),
+            Note::Other(m) => return Some(m)
+        }
+        None
+    }
+
+    fn process(&mut self, buffer: &mut Block) {
+        let sample_rate = buffer.sample_rate() as f32;
+        let gain = self.params.get("A");
+        for (voice, bend) in self.voices.iter_bent() {
+            let note = voice.key.note as f32 + bend * self.params.get("bend");
+            // levels and ratios follow their signals while the note plays
+            voice.fm.set("freq", 440.0 * 2f32.powf((note - 69.0) / 12.0));
+            for p in ["algorithm", "feedback"].iter().chain(RATIO.iter()).chain(LEVEL.iter()) {
+                voice.fm.set(p, self.params.get(p));
+            }
+            let amp = gain * voice.velocity;
+            for frame in buffer.frames_mut() {
+                let v = voice.fm.next(sample_rate) * amp;
+                frame.iter_mut().for_each(|s| *s += v);
+            }
+        }
+        self.voices.retain();
+    }
+
+    fn is_active(&self) -> bool {
+        !self.voices.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
+    }
+}
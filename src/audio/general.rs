@@ -1,4 +1,6 @@
 use crate::audio::parameters::Parameters;
+use crate::audio::Block;
+use crate::audio::processor::{ParamDescriptor, Processor};
 
 pub struct General {
     params: Parameters<f32>,
@@ -8,12 +10,20 @@ impl Default for General {
         Self { params: Parameters::default() }
     }
 }
-impl General {
-    pub fn param(&mut self, key: &str, value: f32) {
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "master gain", 0.0, 1.0, 1.0)
+];
+
+impl Processor for General {
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
         self.params.update(key, &value);
     }
 
-    pub fn process(&mut self, buffer: &mut nannou_audio::Buffer) {
+    fn process(&mut self, buffer: &mut Block) {
         let amp = self.params.get("A");
         for frame in buffer.frames_mut() {
             for sample in frame.iter_mut() {
@@ -0,0 +1,218 @@
+use crate::audio::parameters::Parameters;
+use crate::audio::{AudioMessage, Block, Sample, VoiceKey};
+use crate::audio::nodes::Rng;
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::voices::{self, Note, Voices};
+
+/// Upper bound on grains playing at once, new grains are skipped past this.
+const MAX_GRAINS: usize = 128;
+/// Upper bound on held notes spawning grains.
+const MAX_CLOUDS: usize = 16;
+/// Note the sample plays at its own pitch.
+const ROOT_NOTE: u8 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Window {
+    Hann,
+    Triangle,
+    /// flat with short fades at either end
+    Tukey
+}
+impl Window {
+    fn from_param(value: f32) -> Self {
+        match value.round() as i32 {
+            1 => Window::Triangle,
+            2 => Window::Tukey,
+            _ => Window::Hann
+        }
+    }
+
+    /// Gain at `t` through the grain, from 0 to 1.
+    fn at(&self, t: f32) -> f32 {
+        match self {
+            Window::Hann => 0.5 - 0.5 * (t * 2.0 * std::f32::consts::PI).cos(),
+            Window::Triangle => 1.0 - (2.0 * t - 1.0).abs(),
+            Window::Tukey => (t.min(1.0 - t) * 10.0).min(1.0)
+        }
+    }
+}
+
+struct Grain {
+    position: f64,
+    rate: f64,
+    length: usize,
+    age: usize,
+    gain: f32,
+    window: Window
+}
+
+/// A held note spawning grains, or the drone while `hold` is up.
+struct Cloud {
+    key: Option<VoiceKey>,
+    velocity: f32,
+    // frames until the next grain
+    wait: f64,
+    // let go of, it is dropped straight away
+    released: bool
+}
+impl Cloud {
+    fn new(key: Option<VoiceKey>, velocity: f32) -> Self {
+        Self { key, velocity, wait: 0.0, released: false }
+    }
+}
+impl voices::Voice for Cloud {
+    fn key(&self) -> Option<VoiceKey> {
+        self.key
+    }
+
+    fn release(&mut self) {
+        self.released = true;
+    }
+
+    fn is_released(&self) -> bool {
+        self.released
+    }
+
+    fn is_done(&self) -> bool {
+        self.released
+    }
+
+    // its grains play out on their windows, so it can stop straight away
+    fn steal(&mut self) {
+        self.released = true;
+    }
+}
+
+/// Plays overlapping grains of a sound from the pool while notes of devices
+/// mapped to it by name are held, pitched from middle C.
+pub struct Granular {
+    params: Parameters<f32>,
+    sample: Option<Sample>,
+    clouds: Voices<Cloud>,
+    grains: Vec<Grain>,
+    // for the spray
+    random: Rng
+}
+impl Granular {
+    pub fn new(sample: Option<Sample>) -> Self {
+        Self {
+            params: Parameters::default(),
+            sample,
+            // one more for the drone
+            clouds: Voices::new(MAX_CLOUDS + 1),
+            grains: Vec::with_capacity(MAX_GRAINS),
+            random: Rng::new(0x2545_f491)
+        }
+    }
+
+    fn spawn(&mut self, note: Option<u8>, velocity: f32, sample_rate: f32) {
+        let len_frames = match self.sample.as_ref() {
+            Some(s) => s.len_frames(),
+            None => return
+        };
+        if self.grains.len() >= MAX_GRAINS || len_frames == 0 {
+            return;
+        }
+        let p = &self.params;
+        let semitones = p.get("pitch") + note.map_or(0.0, |n| n as f32 - ROOT_NOTE as f32);
+        let (position, spray, size, window) = (p.get("position"), p.get("spray"), p.get("size"), p.get("window"));
+        let start = (position + spray * self.random.next()).max(0.0).min(1.0);
+        self.grains.push(Grain {
+            position: start as f64 * (len_frames - 1) as f64,
+            rate: 2f64.powf(semitones as f64 / 12.0),
+            length: (size * sample_rate).max(1.0) as usize,
+            age: 0,
+            gain: velocity,
+            window: Window::from_param(window)
+        });
+    }
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of every grain", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("position", "where grains start in the sound, 0 to 1", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("size", "seconds of each grain", 0.005, 1.0, 0.1),
+    ParamDescriptor::new("density", "grains started each second", 1.0, 200.0, 20.0),
+    ParamDescriptor::new("pitch", "semitones the grains are played up", -24.0, 24.0, 0.0),
+    ParamDescriptor::new("spray", "random spread of the start around the position", 0.0, 1.0, 0.02),
+    ParamDescriptor::new("window", "0 hann, 1 triangle, 2 flat with short fades", 0.0, 2.0, 0.0),
+    ParamDescriptor::new("hold", "above 0.5 grains play without a note, at the root pitch", 0.0, 1.0, 0.0)
+];
+
+impl Processor for Granular {
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
+        self.params.update(key, &value);
+    }
+
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        match self.clouds.route(message) {
+            Note::On { key, velocity } => self.clouds.start(Cloud::new(Some(key), velocity)),
+            Note::Off { key } => {
+                self.clouds.release(&key);
+                self.clouds.retain();
+            }
+            Note::Bend => (),
+            Note::Other(m) => return Some(m)
+        }
+        None
+    }
+
+    fn process(&mut self, buffer: &mut Block) {
+        let sample = match self.sample.clone() {
+            Some(s) => s,
+            None => return
+        };
+        let sample_rate = buffer.sample_rate() as f32;
+        let gain = self.params.get("A");
+        // stolen clouds spawn no more grains
+        self.clouds.retain();
+        let interval = sample_rate as f64 / self.params.get("density").max(1.0) as f64;
+
+        // the drone is a cloud without a key
+        let holding = self.clouds.iter().any(|c| c.key.is_none());
+        if self.params.get("hold") > 0.5 && !holding {
+            self.clouds.start(Cloud::new(None, 1.0));
+        } else if self.params.get("hold") <= 0.5 && holding {
+            self.clouds.iter_mut().filter(|c| c.key.is_none()).for_each(|c| c.released = true);
+            self.clouds.retain();
+        }
+
+        for frame in buffer.frames_mut() {
+            for i in 0..self.clouds.len() {
+                self.clouds[i].wait -= 1.0;
+                if self.clouds[i].wait <= 0.0 {
+                    self.clouds[i].wait += interval;
+                    let (note, velocity) = (self.clouds[i].key.as_ref().map(|k| k.note), self.clouds[i].velocity);
+                    self.spawn(note, velocity, sample_rate);
+                }
+            }
+            for grain in self.grains.iter_mut() {
+                let amp = gain * grain.gain * grain.window.at(grain.age as f32 / grain.length as f32);
+                for (channel, s) in frame.iter_mut().enumerate().take(sample.channels) {
+                    *s += sample.at(channel, grain.position) * amp;
+                }
+                grain.position += grain.rate;
+                grain.age += 1;
+            }
+            let len_frames = sample.len_frames() as f64;
+            self.grains.retain(|g| g.age < g.length && g.position < len_frames);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        !self.clouds.is_empty() || !self.grains.is_empty()
+    }
+
+    fn replace_sample(&mut self, sample: Option<Sample>) -> Option<Sample> {
+        std::mem::replace(&mut self.sample, sample)
+    }
+
+    fn reset(&mut self) {
+        self.clouds.clear();
+        self.grains.clear();
+    }
+}
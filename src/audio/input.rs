@@ -0,0 +1,178 @@
+use crossbeam::channel::{Sender, Receiver, bounded};
+use nannou_audio::cpal::traits::DeviceTrait;
+use ringbuf::{RingBuffer, Producer, Consumer};
+
+use crate::audio::Block;
+use crate::config::Config;
+
+/// Seconds of input the ring buffer can hold.
+const BUFFER_SECONDS: usize = 1;
+/// Blocks of input allowed to queue up before the oldest is dropped, keeps
+/// the monitoring latency down when the input and output clocks drift.
+const MAX_QUEUED_BLOCKS: usize = 4;
+
+/// State of the capture callback, it maps the device channels onto the
+/// output channels and hands them to the output stream.
+pub struct Capture {
+    producer: Producer<f32>,
+    // input channel for each output channel
+    routing: Vec<Vec<usize>>,
+    gain: f32,
+    frame: Vec<f32>
+}
+
+/// A capture of the input into a sound key, filled on the audio thread.
+pub struct Take {
+    pub key: String,
+    /// interleaved in the output format
+    pub data: Vec<f32>,
+    done: Sender<Take>
+}
+impl Take {
+    /// Room for `max_samples` is allocated up front, the receiver gets the
+    /// take back once it is stopped.
+    pub fn new(key: &str, max_samples: usize) -> (Take, Receiver<Take>) {
+        let (done, done_rx) = bounded(1);
+        (Take { key: key.to_string(), data: Vec::with_capacity(max_samples), done }, done_rx)
+    }
+
+    /// Hand the take back to whoever started it, it is freed there.
+    pub fn finish(self) {
+        let done = self.done.clone();
+        done.try_send(self).ok();
+    }
+}
+
+/// The output side of an input stream.
+pub struct Input {
+    consumer: Consumer<f32>,
+    monitor: bool,
+    takes: Vec<Take>
+}
+impl Input {
+    /// Mix the captured input onto the buffer and into any takes.
+    pub fn process(&mut self, buffer: &mut Block) {
+        let len = buffer.len();
+        let queued = self.consumer.len();
+        if queued > len * MAX_QUEUED_BLOCKS {
+            // whole frames only, so the channels stay in place
+            let excess = queued - len;
+            self.consumer.discard(excess - excess % buffer.channels());
+        }
+        let Input { consumer, monitor, takes } = self;
+        let mut i = 0;
+        consumer.pop_each(|s| {
+            if *monitor {
+                buffer[i] += s;
+            }
+            for take in takes.iter_mut() {
+                // full takes stop growing rather than reallocate
+                if take.data.len() < take.data.capacity() {
+                    take.data.push(s);
+                }
+            }
+            i += 1;
+            true
+        }, Some(len));
+    }
+
+    pub fn start(&mut self, take: Take) {
+        self.takes.push(take);
+    }
+
+    /// Hand the take for `key` back to whoever started it.
+    pub fn stop(&mut self, key: &str) {
+        if let Some(i) = self.takes.iter().position(|t| t.key == key) {
+            self.takes.swap_remove(i).finish();
+        }
+    }
+}
+
+/// Open an input stream on the first audio device in run.toml that has one.
+/// The stream stops when dropped.
+pub fn open(host: &nannou_audio::Host, cfg: &Config) -> Option<(nannou_audio::Stream<Capture>, Input)> {
+    let device = cfg.audio_inputs().into_iter().next()?;
+    let input_device = match find_device(host, &device.name) {
+        Some(d) => d,
+        None => {
+            println!("Audio input not found: {}", device.name);
+            return None;
+        }
+    };
+    let capacity = cfg.audio.sample_rate as usize * cfg.audio.channels * BUFFER_SECONDS;
+    let (producer, consumer) = RingBuffer::<f32>::new(capacity).split();
+    let capture = Capture {
+        producer,
+        routing: routing(device.channels.as_ref(), cfg.audio.channels),
+        gain: device.gain,
+        frame: vec![0.0; cfg.audio.channels]
+    };
+    let stream = host
+        .new_input_stream(capture)
+        .capture(capture_fn)
+        .device(input_device)
+        .sample_rate(cfg.audio.sample_rate)
+        .build();
+    match stream {
+        Ok(stream) => {
+            println!("Audio input {}", device.name);
+            Some((stream, Input { consumer, monitor: device.monitor, takes: Vec::with_capacity(8) }))
+        }
+        Err(e) => {
+            println!("Unable to open audio input {}: {:?}", device.name, e);
+            None
+        }
+    }
+}
+
+fn find_device(host: &nannou_audio::Host, name: &str) -> Option<nannou_audio::Device> {
+    host.input_devices().ok()?.find(|d| d.name().map_or(false, |n| n == name))
+}
+
+/// The inputs feeding each output, from channels counting from 1. One input
+/// goes to every output, more go round the outputs in order. Without
+/// `channels` the first inputs feed the outputs one to one.
+fn routing(channels: Option<&Vec<usize>>, outputs: usize) -> Vec<Vec<usize>> {
+    let inputs: Vec<usize> = match channels {
+        Some(channels) => channels.iter().filter(|c| **c > 0).map(|c| c - 1).collect(),
+        None => (0..outputs).collect()
+    };
+    let mut routing = vec![vec![]; outputs];
+    if inputs.len() == 1 {
+        routing.iter_mut().for_each(|r| r.push(inputs[0]));
+    } else {
+        for (i, input) in inputs.into_iter().enumerate() {
+            routing[i % outputs].push(input);
+        }
+    }
+    routing
+}
+
+fn capture_fn(capture: &mut Capture, buffer: &nannou_audio::Buffer) {
+    let Capture { producer, routing, gain, frame } = capture;
+    for input in buffer.frames() {
+        for (out, sources) in frame.iter_mut().zip(routing.iter()) {
+            *out = sources.iter().filter_map(|c| input.get(*c)).sum::<f32>() * *gain;
+        }
+        // when the output side stalls the newest input is dropped, whole frames
+        // only so the channels stay in order
+        if producer.remaining() >= frame.len() {
+            producer.push_slice(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routings() {
+        assert_eq!(routing(None, 2), vec![vec![0], vec![1]]);
+        // a single channel goes to both sides
+        assert_eq!(routing(Some(&vec![3]), 2), vec![vec![2], vec![2]]);
+        assert_eq!(routing(Some(&vec![1, 2, 3]), 2), vec![vec![0, 2], vec![1]]);
+        // channels count from 1
+        assert_eq!(routing(Some(&vec![0, 2]), 2), vec![vec![1], vec![1]]);
+    }
+}
@@ -0,0 +1,254 @@
+use std::f64::consts::PI;
+
+use crate::audio::envelope::Envelope;
+use crate::config::{Adsr, FilterMode, Wave};
+
+/// Frames of the patch graph, always stereo.
+pub type Frame = [f32; 2];
+
+/// Replaces its inputs with a waveform. The saw and pulse have their steps
+/// smoothed with PolyBLEP so they don't alias, the triangle is the integral
+/// of the smoothed square.
+#[derive(Debug)]
+pub struct Oscillator {
+    wave: Wave,
+    phase: f64,
+    freq: f32,
+    amp: f32,
+    width: f32,
+    // the integrated square of the triangle
+    integral: f32
+}
+impl Oscillator {
+    pub fn new(wave: Wave) -> Self {
+        Self { wave, phase: 0.0, freq: 440.0, amp: 1.0, width: 0.5, integral: -1.0 }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        match param {
+            "freq" => self.freq = value,
+            "amp" => self.amp = value,
+            "width" => self.width = value,
+            _ => ()
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+        self.integral = -1.0;
+    }
+
+    fn pulse(&self, t: f64, dt: f64, width: f64) -> f32 {
+        let naive = if t < width { 1.0 } else { -1.0 };
+        naive + poly_blep(t, dt) - poly_blep((t + 1.0 - width).fract(), dt)
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame], sample_hz: f64) {
+        let step = (self.freq as f64 / sample_hz).max(0.0).min(0.5);
+        let width = (self.width as f64).max(0.01).min(0.99);
+        for frame in buffer.iter_mut() {
+            let t = self.phase;
+            let v = match self.wave {
+                Wave::Sine => (t * PI * 2.0).sin() as f32,
+                Wave::Saw => (2.0 * t - 1.0) as f32 - poly_blep(t, step),
+                Wave::Pulse => self.pulse(t, step, width),
+                Wave::Triangle => {
+                    // leaks a little so it can't drift away from zero
+                    let square = self.pulse(t, step, 0.5);
+                    self.integral = self.integral * (1.0 - 0.05 * step as f32) + 4.0 * step as f32 * square;
+                    self.integral
+                }
+            } * self.amp;
+            *frame = [v, v];
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+/// The correction of a step from 1 to -1 at phase 0, non zero within a sample of it.
+fn poly_blep(t: f64, dt: f64) -> f32 {
+    if dt <= 0.0 {
+        0.0
+    } else if t < dt {
+        let t = t / dt;
+        (t + t - t * t - 1.0) as f32
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        (t * t + t + t + 1.0) as f32
+    } else {
+        0.0
+    }
+}
+
+/// Xorshift random numbers, cheap enough for the audio thread.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    // never zero, xorshift would stay there
+    state: u32
+}
+impl Rng {
+    pub fn new(seed: u32) -> Self {
+        Self { state: seed.max(1) }
+    }
+
+    /// Between -1 and 1.
+    pub fn next(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+}
+
+/// White noise in place of its inputs, the same on both channels.
+#[derive(Debug)]
+pub struct Noise {
+    amp: f32,
+    rng: Rng
+}
+impl Noise {
+    pub fn new() -> Self {
+        Self { amp: 1.0, rng: Rng::new(0x9e37_79b9) }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        if param == "amp" {
+            self.amp = value;
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame]) {
+        for frame in buffer.iter_mut() {
+            let v = self.rng.next() * self.amp;
+            *frame = [v, v];
+        }
+    }
+}
+
+/// Resonant two pole filter, the biquads of the Audio EQ Cookbook.
+#[derive(Debug)]
+pub struct Filter {
+    mode: FilterMode,
+    cutoff: f32,
+    q: f32,
+    // coefficients for the cutoff and q they were worked out at
+    coefficients: [f32; 5],
+    designed: (f32, f32, f64),
+    // transposed direct form II state per channel
+    state: [[f32; 2]; 2]
+}
+impl Filter {
+    pub fn new(mode: FilterMode) -> Self {
+        Self { mode, cutoff: 1000.0, q: 0.707, coefficients: [1.0, 0.0, 0.0, 0.0, 0.0], designed: (0.0, 0.0, 0.0), state: [[0.0; 2]; 2] }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        match param {
+            "cutoff" => self.cutoff = value,
+            "q" => self.q = value,
+            _ => ()
+        }
+    }
+
+    fn design(&mut self, sample_hz: f64) {
+        if self.designed == (self.cutoff, self.q, sample_hz) {
+            return;
+        }
+        self.designed = (self.cutoff, self.q, sample_hz);
+        let cutoff = (self.cutoff as f64).max(10.0).min(sample_hz * 0.45);
+        let q = (self.q as f64).max(0.05);
+        let w0 = 2.0 * PI * cutoff / sample_hz;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q);
+        let (b0, b1, b2) = match self.mode {
+            FilterMode::Lowpass => ((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0),
+            FilterMode::Highpass => ((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0),
+            FilterMode::Bandpass => (alpha, 0.0, -alpha)
+        };
+        let a0 = 1.0 + alpha;
+        self.coefficients = [
+            (b0 / a0) as f32, (b1 / a0) as f32, (b2 / a0) as f32,
+            (-2.0 * cos / a0) as f32, ((1.0 - alpha) / a0) as f32
+        ];
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame], sample_hz: f64) {
+        self.design(sample_hz);
+        let [b0, b1, b2, a1, a2] = self.coefficients;
+        for frame in buffer.iter_mut() {
+            for (x, s) in frame.iter_mut().zip(self.state.iter_mut()) {
+                let y = b0 * *x + s[0];
+                s[0] = b1 * *x - a1 * y + s[1];
+                s[1] = b2 * *x - a2 * y;
+                *x = y;
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = [[0.0; 2]; 2];
+    }
+}
+
+/// Shapes its inputs with an envelope, started when the gate goes above 0.5
+/// and released when it falls back.
+#[derive(Debug)]
+pub struct Gate {
+    adsr: Adsr,
+    gate: f32,
+    envelope: Option<Envelope>
+}
+impl Gate {
+    pub fn new(adsr: Adsr) -> Self {
+        Self { adsr, gate: 0.0, envelope: None }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        if param == "gate" {
+            self.gate = value;
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame], sample_hz: f64) {
+        let open = self.envelope.as_ref().map_or(false, |e| !e.is_released());
+        if self.gate > 0.5 && !open {
+            self.envelope = Some(Envelope::new(self.adsr, sample_hz as u32));
+        } else if self.gate <= 0.5 && open {
+            self.envelope.as_mut().unwrap().release();
+        }
+        for frame in buffer.iter_mut() {
+            let amp = match self.envelope.as_mut() {
+                Some(e) if !e.is_idle() => e.next(),
+                _ => 0.0
+            };
+            frame.iter_mut().for_each(|s| *s *= amp);
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = None;
+    }
+}
+
+/// Sums its inputs, which the graph does already, and scales them.
+#[derive(Debug)]
+pub struct Mixer {
+    gain: f32
+}
+impl Mixer {
+    pub fn new() -> Self {
+        Self { gain: 1.0 }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        if param == "gain" {
+            self.gain = value;
+        }
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame]) {
+        for frame in buffer.iter_mut() {
+            frame.iter_mut().for_each(|s| *s *= self.gain);
+        }
+    }
+}
@@ -23,11 +23,16 @@ impl<R> Parameters<R>
             }
         }
     } 
+    /// Only a key set for the first time is allocated, processors have theirs set
+    /// when the chain is built so binding them on the audio thread never allocates.
     pub fn update(&mut self, key: &str, value: &R) {
-        if !self.defaults.contains_key(key) {
-            self.defaults.insert(key.to_string(), *value);
+        match self.h.get_mut(key) {
+            Some(v) => *v = *value,
+            None => {
+                self.defaults.entry(key.to_string()).or_insert(*value);
+                self.h.insert(key.to_string(), *value);
+            }
         }
-        self.h.insert(key.to_string(), *value);
     }
 }
 
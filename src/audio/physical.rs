@@ -0,0 +1,258 @@
+use crate::audio::parameters::Parameters;
+use crate::audio::{AudioMessage, Block, VoiceKey};
+use crate::audio::nodes::Rng;
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::voices::{self, Note, Voices};
+
+/// Upper bound on simultaneous voices, the oldest voice is stolen past this.
+const MAX_VOICES: usize = 16;
+/// Longest string delay line, 10Hz at 44.1kHz.
+const MAX_DELAY: usize = 4096;
+/// Peak level a voice is dropped below.
+const SILENCE: f32 = 0.0001;
+/// Frequencies of the modes of each mallet material, relative to the note.
+const MATERIALS: [&[f32]; 3] = [
+    // marimba bar
+    &[1.0, 3.93, 10.72, 20.2],
+    // vibraphone bar
+    &[1.0, 4.0, 10.0, 19.0],
+    // glass, a bit like a bell
+    &[1.0, 2.32, 4.25, 6.63, 9.38]
+];
+const MAX_MODES: usize = 5;
+
+/// Which instrument a processor plays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    /// Karplus-Strong plucked string
+    Pluck,
+    /// a bank of resonators struck like a mallet on a bar
+    Mallet
+}
+
+/// A burst of noise circulating through a delay line a period long, losing a
+/// little of its highs each time round.
+struct Strand {
+    delay: [f32; MAX_DELAY],
+    write: usize,
+    length: f32,
+    // the last frame read, averaged with the next by the loop filter
+    last: f32
+}
+impl Strand {
+    fn new(freq: f32, sample_rate: f32, velocity: f32, brightness: f32, damping: f32, random: &mut Rng) -> Self {
+        // the loop filter delays by `damping` frames
+        let length = (sample_rate / freq - damping).max(2.0).min(MAX_DELAY as f32 - 2.0);
+        let mut delay = [0.0; MAX_DELAY];
+        // harder plucks are brighter, the darkest still sounds
+        let smooth = 0.9 - 0.9 * (brightness * (0.5 + 0.5 * velocity)).min(1.0);
+        let mut low = 0.0;
+        for s in delay.iter_mut().take(length.ceil() as usize) {
+            low = low * smooth + random.next() * (1.0 - smooth);
+            *s = low * velocity;
+        }
+        Self { delay, write: length.ceil() as usize % MAX_DELAY, length, last: 0.0 }
+    }
+
+    fn next(&mut self, loss: f32, damping: f32) -> f32 {
+        let read = (self.write as f32 - self.length + MAX_DELAY as f32) % MAX_DELAY as f32;
+        let i = read as usize;
+        let a = self.delay[i];
+        let b = self.delay[(i + 1) % MAX_DELAY];
+        let y = a + (b - a) * read.fract();
+        // damping 0.5 is the average of the original algorithm
+        self.delay[self.write] = (y * (1.0 - damping) + self.last * damping) * loss;
+        self.last = y;
+        self.write = (self.write + 1) % MAX_DELAY;
+        y
+    }
+}
+
+/// Two pole resonators, one per mode, rung by a single strike.
+struct Modes {
+    // coefficients and state of each mode: frequency term, and the last two outputs
+    coefficients: [f32; MAX_MODES],
+    state: [[f32; 2]; MAX_MODES],
+    count: usize
+}
+impl Modes {
+    fn new(freq: f32, sample_rate: f32, velocity: f32, brightness: f32, material: &[f32]) -> Self {
+        let mut modes = Self { coefficients: [0.0; MAX_MODES], state: [[0.0; 2]; MAX_MODES], count: 0 };
+        for (i, ratio) in material.iter().enumerate().take(MAX_MODES) {
+            let w = 2.0 * std::f32::consts::PI * freq * ratio / sample_rate;
+            // modes past the Nyquist frequency would alias
+            if w >= std::f32::consts::PI {
+                break;
+            }
+            // a soft mallet barely rings the higher modes, a hard one rings them more
+            let amp = velocity * (brightness * (0.5 + 0.5 * velocity)).powi(i as i32);
+            modes.coefficients[i] = 2.0 * w.cos();
+            // the strike is an impulse, starting the mode at its peak
+            modes.state[i] = [amp * w.sin(), 0.0];
+            modes.count = i + 1;
+        }
+        modes
+    }
+
+    /// `loss` is applied per frame to the lowest mode, higher ones lose more.
+    fn next(&mut self, loss: f32, damping: f32) -> f32 {
+        let mut out = 0.0;
+        for i in 0..self.count {
+            let r = loss.powf(1.0 + damping * i as f32 * 2.0);
+            let [y1, y2] = self.state[i];
+            let y = r * self.coefficients[i] * y1 - r * r * y2;
+            self.state[i] = [y, y1];
+            out += y;
+        }
+        out / self.count.max(1) as f32
+    }
+}
+
+// kept inline so starting a note doesn't allocate on the audio thread
+enum Body {
+    Strand(Strand),
+    Modes(Modes)
+}
+
+struct Voice {
+    key: VoiceKey,
+    body: Body,
+    freq: f32,
+    released: bool,
+    // gain of a stolen voice, fading to nothing
+    fade: Option<f32>,
+    done: bool
+}
+impl voices::Voice for Voice {
+    fn key(&self) -> Option<VoiceKey> {
+        Some(self.key)
+    }
+
+    fn release(&mut self) {
+        self.released = true;
+    }
+
+    fn is_released(&self) -> bool {
+        self.released
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn steal(&mut self) {
+        self.released = true;
+        self.fade = Some(1.0);
+    }
+}
+
+/// Plucked strings or struck bars played from devices mapped to them by name.
+pub struct Physical {
+    model: Model,
+    params: Parameters<f32>,
+    sample_rate: u32,
+    voices: Voices<Voice>,
+    // for the pluck noise
+    random: Rng
+}
+impl Physical {
+    pub fn new(model: Model) -> Self {
+        Self { model, params: Parameters::default(), sample_rate: 44100, voices: Voices::new(MAX_VOICES), random: Rng::new(0x1234_5678) }
+    }
+
+    fn on(&mut self, key: VoiceKey, velocity: f32) {
+        let freq = 440.0 * 2f32.powf((key.note as f32 - 69.0) / 12.0);
+        let sample_rate = self.sample_rate as f32;
+        let brightness = self.params.get("brightness");
+        let body = match self.model {
+            Model::Pluck => Body::Strand(Strand::new(freq, sample_rate, velocity, brightness, self.params.get("damping"), &mut self.random)),
+            Model::Mallet => {
+                let material = MATERIALS[(self.params.get("material").round() as usize).min(MATERIALS.len() - 1)];
+                Body::Modes(Modes::new(freq, sample_rate, velocity, brightness, material))
+            }
+        };
+        self.voices.start(Voice { key, body, freq, released: false, fade: None, done: false });
+    }
+}
+
+/// Gain per frame that fades by 60dB over `seconds`.
+fn loss(seconds: f32, sample_rate: f32) -> f32 {
+    10f32.powf(-3.0 / (seconds.max(0.001) * sample_rate))
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of every voice", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("decay", "seconds a held note takes to fade", 0.05, 30.0, 4.0),
+    ParamDescriptor::new("release", "seconds a note takes to fade once let go", 0.01, 30.0, 0.3),
+    ParamDescriptor::new("damping", "how much faster the highs fade", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("brightness", "highs of the pluck or strike", 0.0, 1.0, 0.7),
+    ParamDescriptor::new("material", "mallets only, 0 marimba, 1 vibraphone, 2 glass", 0.0, 2.0, 0.0)
+];
+
+impl Processor for Physical {
+    fn prepare(&mut self, sample_rate: u32, _block_size: usize) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
+        self.params.update(key, &value);
+    }
+
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        match self.voices.route(message) {
+            Note::On { key, velocity } => self.on(key, velocity),
+            Note::Off { key } => self.voices.release(&key),
+            Note::Bend => (),
+            Note::Other(m) => return Some(m)
+        }
+        None
+    }
+
+    fn process(&mut self, buffer: &mut Block) {
+        let sample_rate = buffer.sample_rate() as f32;
+        let gain = self.params.get("A");
+        let damping = self.params.get("damping");
+        let (decay, release) = (self.params.get("decay"), self.params.get("release"));
+        // a stolen voice fades out over STEAL_TIME
+        let step = 1.0 / (voices::STEAL_TIME * sample_rate);
+        for voice in self.voices.iter_mut() {
+            let seconds = if voice.released { release } else { decay };
+            let loss = match voice.body {
+                // the loop runs once a period, so the loss is per period
+                Body::Strand(_) => loss(seconds, voice.freq),
+                Body::Modes(_) => loss(seconds, sample_rate)
+            };
+            let mut peak: f32 = 0.0;
+            for frame in buffer.frames_mut() {
+                let v = match voice.body {
+                    Body::Strand(ref mut strand) => strand.next(loss, damping),
+                    Body::Modes(ref mut modes) => modes.next(loss, damping)
+                };
+                let v = match voice.fade.as_mut() {
+                    Some(fade) => {
+                        *fade = (*fade - step).max(0.0);
+                        v * *fade
+                    }
+                    None => v
+                };
+                frame.iter_mut().for_each(|s| *s += v * gain);
+                peak = peak.max(v.abs());
+            }
+            // a silent block ends the voice, before the gain so turning it down doesn't
+            voice.done = peak < SILENCE || voice.fade == Some(0.0);
+        }
+        self.voices.retain();
+    }
+
+    fn is_active(&self) -> bool {
+        !self.voices.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
+    }
+}
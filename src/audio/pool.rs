@@ -0,0 +1,232 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use crate::config::{AudioConfig, Sounds};
+use crate::sf2;
+
+/// Zero crossings on each side of the resampling kernel.
+const SINC_ZEROS: usize = 16;
+/// Kernel table entries per zero crossing, values in between are interpolated.
+const SINC_RESOLUTION: usize = 512;
+
+/// A sound decoded into interleaved f32 samples, cheap to clone and safe to
+/// hand to the audio thread.
+#[derive(Clone)]
+pub struct Sample {
+    pub data: Arc<[f32]>,
+    pub channels: usize,
+    pub sample_rate: u32,
+    /// sample rate of the file before it was converted
+    pub source_rate: u32,
+    /// loop from the smpl chunk of a wav file, as frames of `data`
+    pub loop_points: Option<(usize, usize)>
+}
+impl std::fmt::Debug for Sample {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Sample({} frames)", self.len_frames())
+    }
+}
+impl Sample {
+    /// Decode `path` and convert it to the channel count and sample rate of the output stream.
+    pub fn load(path: &str, format: &AudioConfig) -> Option<Sample> {
+        // read once, the loop points come from the same bytes as the audio
+        let bytes = std::fs::read(path).ok()?;
+        let mut reader = audrey::Reader::new(std::io::Cursor::new(&bytes[..])).ok()?;
+        let description = reader.description();
+        let data: Vec<f32> = reader.samples::<f32>().filter_map(Result::ok).collect();
+        let mut sample = Sample::new(&data, description.channel_count() as usize, description.sample_rate(), format);
+        sample.loop_points = read_smpl_loop(&bytes).map(|(start, end)| (sample.to_frames(start), sample.to_frames(end)));
+        Some(sample)
+    }
+
+    /// Convert interleaved samples to the channel count and sample rate of the output stream.
+    pub fn new(data: &[f32], channels: usize, sample_rate: u32, format: &AudioConfig) -> Sample {
+        let converted = convert_channels(data, channels, format.channels);
+        let converted = converted.iter()
+            .map(|c| resample(c, sample_rate, format.sample_rate))
+            .collect::<Vec<_>>();
+        Sample {
+            data: interleave(&converted).into(),
+            channels: format.channels,
+            sample_rate: format.sample_rate,
+            source_rate: sample_rate,
+            loop_points: None
+        }
+    }
+
+    /// Convert a frame position in the source file to one in `data`.
+    pub fn to_frames(&self, source_frame: usize) -> usize {
+        (source_frame as f64 * self.sample_rate as f64 / self.source_rate as f64).round() as usize
+    }
+
+    pub fn len_frames(&self) -> usize {
+        self.data.len() / self.channels
+    }
+
+    /// Value of `channel` at a fractional frame position, using a 4 point
+    /// Hermite interpolation. Positions past either end read as the edge frame.
+    pub fn at(&self, channel: usize, position: f64) -> f32 {
+        let last = self.len_frames() as isize - 1;
+        let i = position.floor() as isize;
+        let t = (position - i as f64) as f32;
+        let x = |n: isize| self.data[n.max(0).min(last) as usize * self.channels + channel];
+        let (xm1, x0, x1, x2) = (x(i - 1), x(i), x(i + 1), x(i + 2));
+        let c1 = 0.5 * (x1 - xm1);
+        let c2 = xm1 - 2.5 * x0 + 2.0 * x1 - 0.5 * x2;
+        let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+        ((c3 * t + c2) * t + c1) * t + x0
+    }
+}
+
+/// Every sound referenced by `config::Sounds`, decoded up front and keyed by path.
+#[derive(Clone)]
+pub struct SamplePool {
+    h: HashMap<String, Sample>
+}
+impl Default for SamplePool {
+    fn default() -> Self {
+        Self { h: HashMap::new() }
+    }
+}
+impl std::fmt::Debug for SamplePool {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "SamplePool({} samples)", self.h.len())
+    }
+}
+impl SamplePool {
+    /// `fonts` are the soundfonts read by `Sounds::load`, keyed by file.
+    pub fn load(sounds: &Sounds, fonts: &HashMap<String, sf2::SoundFont>, format: &AudioConfig) -> Self {
+        let mut pool = Self::default();
+        for sound in sounds.iter() {
+            if pool.h.contains_key(&sound.path) {
+                continue;
+            }
+            let sample = match sf2::split_path(&sound.path) {
+                Some((file, left, right)) => {
+                    fonts.get(file).and_then(|font| {
+                        let rate = font.samples.get(left)?.sample_rate;
+                        let data = font.sample_data(left, right)?;
+                        let channels = if right.is_some() { 2 } else { 1 };
+                        Some(Sample::new(&data, channels, rate, format))
+                    })
+                }
+                None => Sample::load(&sound.path, format)
+            };
+            match sample {
+                Some(sample) => {
+                    pool.h.insert(sound.path.clone(), sample);
+                }
+                None => println!("Unable to load sound {}", &sound.path)
+            }
+        }
+        println!("Loaded {} samples", pool.h.len());
+        pool
+    }
+
+    pub fn get(&self, path: &str) -> Option<Sample> {
+        self.h.get(path).cloned()
+    }
+
+    pub fn insert(&mut self, path: &str, sample: Sample) {
+        self.h.insert(path.to_string(), sample);
+    }
+}
+
+/// Read the first loop of the smpl chunk in the bytes of a wav file, as the
+/// start frame and the frame after the end.
+fn read_smpl_loop(bytes: &[u8]) -> Option<(usize, usize)> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return None;
+    }
+    let u32_at = |i: usize| -> Option<usize> {
+        let b = bytes.get(i..i + 4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    let mut i = 12;
+    while i + 8 <= bytes.len() {
+        let size = u32_at(i + 4)?;
+        if &bytes[i..i + 4] == b"smpl" {
+            // 36 bytes of header, the loop count is at 28, then 24 bytes per loop
+            let loops = u32_at(i + 8 + 28)?;
+            if loops == 0 {
+                return None;
+            }
+            let start = u32_at(i + 8 + 36 + 8)?;
+            // the end frame is played too
+            let end = u32_at(i + 8 + 36 + 12)? + 1;
+            return if end > start { Some((start, end)) } else { None };
+        }
+        // chunks are padded to an even size
+        i += 8 + size + (size & 1);
+    }
+    None
+}
+
+/// Split interleaved samples into one buffer per output channel. Extra output
+/// channels repeat the source channels (mono to stereo), fewer output channels
+/// average the source channels that fold onto them (stereo to mono).
+fn convert_channels(data: &[f32], from: usize, to: usize) -> Vec<Vec<f32>> {
+    let frames = data.len() / from;
+    let mut out = vec![Vec::with_capacity(frames); to];
+    for frame in data.chunks_exact(from) {
+        for (c, channel) in out.iter_mut().enumerate() {
+            if to >= from {
+                channel.push(frame[c % from]);
+            } else {
+                let folded = frame.iter().skip(c).step_by(to);
+                let count = folded.clone().count() as f32;
+                channel.push(folded.sum::<f32>() / count);
+            }
+        }
+    }
+    out
+}
+
+/// Resample a single channel with a Blackman windowed sinc. When going down in
+/// rate the kernel is widened so it also filters out what would alias.
+fn resample(channel: &[f32], from: u32, to: u32) -> Vec<f32> {
+    if from == to || channel.is_empty() {
+        return channel.to_vec();
+    }
+    let table = sinc_table();
+    let step = from as f64 / to as f64;
+    let scale = (to as f64 / from as f64).min(1.0);
+    let radius = SINC_ZEROS as f64 / scale;
+    let len = (channel.len() as f64 / step).ceil() as usize;
+    let last = channel.len() - 1;
+    (0..len).map(|n| {
+        let t = n as f64 * step;
+        let lo = (t - radius).ceil().max(0.0) as usize;
+        let hi = ((t + radius).floor() as usize).min(last);
+        let sum: f64 = (lo..=hi)
+            .map(|k| channel[k] as f64 * sinc_kernel(&table, (t - k as f64) * scale))
+            .sum();
+        (sum * scale) as f32
+    }).collect()
+}
+
+fn sinc_table() -> Vec<f64> {
+    use std::f64::consts::PI;
+    (0..=SINC_ZEROS * SINC_RESOLUTION + 1).map(|i| {
+        let x = i as f64 / SINC_RESOLUTION as f64;
+        let sinc = if i == 0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+        // Blackman window over the full kernel width
+        let w = 0.5 + 0.5 * (x / SINC_ZEROS as f64);
+        let window = 0.42 - 0.5 * (2.0 * PI * w).cos() + 0.08 * (4.0 * PI * w).cos();
+        if x >= SINC_ZEROS as f64 { 0.0 } else { sinc * window }
+    }).collect()
+}
+
+fn sinc_kernel(table: &[f64], x: f64) -> f64 {
+    let p = x.abs() * SINC_RESOLUTION as f64;
+    let i = p as usize;
+    if i >= SINC_ZEROS * SINC_RESOLUTION {
+        return 0.0;
+    }
+    let frac = p - i as f64;
+    table[i] + (table[i + 1] - table[i]) * frac
+}
+
+fn interleave(channels: &[Vec<f32>]) -> Vec<f32> {
+    let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    (0..frames).flat_map(|i| channels.iter().map(move |c| c[i])).collect()
+}
@@ -0,0 +1,95 @@
+use crossbeam::channel::Sender;
+
+use crate::audio::{AudioMessage, Block, Sample};
+use crate::audio::chain::Garbage;
+use crate::audio::{sounds, drums, dasp_test, general, synth, fm, granular, physical};
+use crate::audio::parameters::Parameters;
+use crate::config::{Config, ProcessorConfig};
+
+/// A parameter a processor understands, set with `Processor::param`.
+#[derive(Debug, Clone)]
+pub struct ParamDescriptor {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32
+}
+impl ParamDescriptor {
+    pub const fn new(name: &'static str, description: &'static str, min: f32, max: f32, default: f32) -> Self {
+        Self { name, description, min, max, default }
+    }
+}
+
+/// One stage of the audio chain.
+pub trait Processor: Send {
+    /// Called before the first block, when the sample rate changes or a block is
+    /// larger than `block_size`.
+    fn prepare(&mut self, _sample_rate: u32, _block_size: usize) {}
+
+    fn params(&self) -> &'static [ParamDescriptor] {
+        &[]
+    }
+
+    fn param(&mut self, key: &str, value: f32);
+
+    /// Every signal, before each block, for processors that bind their own.
+    fn signals(&mut self, _signals: &Parameters<f32>) {}
+
+    /// The drop thread, before each block, for processors freeing memory as they play.
+    fn garbage(&mut self, _garbage: &Sender<Garbage>) {}
+
+    /// Messages are offered to each processor in chain order, the ones it
+    /// doesn't use are handed back for the next.
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        Some(message)
+    }
+
+    fn process(&mut self, buffer: &mut Block);
+
+    /// Whether something is still sounding without new messages, like voices
+    /// ringing out. Renders keep going until no processor is.
+    fn is_active(&self) -> bool {
+        false
+    }
+
+    /// Drop any state left from earlier blocks, like playing voices or phases.
+    fn reset(&mut self) {}
+
+    /// Play `sample` from now on, for processors playing a sound. The one it
+    /// played is handed back so the audio thread doesn't free it.
+    fn replace_sample(&mut self, sample: Option<Sample>) -> Option<Sample> {
+        sample
+    }
+}
+
+/// Processors by the type name used in run.toml. Synths are played by the
+/// name of their entry, the granular one plays the first sound of its key.
+pub fn create(cfg: &ProcessorConfig, config: &Config) -> Option<Box<dyn Processor>> {
+    let patch = cfg.patch.as_ref().and_then(|path| config.patches.get(path));
+    match cfg.kind.as_str() {
+        "sounds" => Some(Box::new(sounds::Sounds::default())),
+        "drums" => Some(Box::new(drums::Drums::default())),
+        "dasp_test" => Some(Box::new(patch.map_or_else(dasp_test::DaspTestData::default, dasp_test::DaspTestData::from_patch))),
+        "general" => Some(Box::new(general::General::default())),
+        "synth" => Some(Box::new(synth::Synth::default())),
+        "fm" => Some(Box::new(fm::Fm::default())),
+        "pluck" => Some(Box::new(physical::Physical::new(physical::Model::Pluck))),
+        "mallet" => Some(Box::new(physical::Physical::new(physical::Model::Mallet))),
+        "granular" => {
+            let sample = sample(cfg, config);
+            if sample.is_none() {
+                println!("Processor {} has no sound loaded to play", cfg.name());
+            }
+            Some(Box::new(granular::Granular::new(sample)))
+        }
+        _ => None
+    }
+}
+
+/// The sample of the first sound of the processor's sound key.
+pub fn sample(cfg: &ProcessorConfig, config: &Config) -> Option<Sample> {
+    cfg.sound.as_ref()
+        .and_then(|key| config.sounds.iter().find(|s| &s.key == key))
+        .and_then(|sound| config.pool.get(&sound.path))
+}
@@ -0,0 +1,168 @@
+use crossbeam::channel::{Sender, Receiver, bounded, RecvTimeoutError};
+use ringbuf::{RingBuffer, Producer, Consumer};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::audio::Block;
+use crate::config::{Config, RecordFormat};
+
+/// Seconds of audio the ring buffer holds while the writer catches up.
+const BUFFER_SECONDS: usize = 2;
+/// Signal that starts recording above 0.5 and stops it below.
+pub const RECORD_SIGNAL: &str = "record";
+
+enum RecordEvent {
+    Start,
+    Stop
+}
+
+/// The audio thread side of a recording, it copies the final buffer into the
+/// ring buffer without allocating or locking.
+pub struct Recorder {
+    producer: Producer<f32>,
+    events: Sender<RecordEvent>,
+    recording: bool
+}
+impl Recorder {
+    /// Open the ring buffer and start the thread writing recordings.
+    pub fn new(cfg: &Config) -> Self {
+        let capacity = cfg.audio.sample_rate as usize * cfg.audio.channels * BUFFER_SECONDS;
+        let (producer, consumer) = RingBuffer::<f32>::new(capacity).split();
+        // bounded so sending from the audio thread never allocates
+        let (events, events_rx) = bounded(16);
+        let writer = Writer {
+            consumer,
+            events: events_rx,
+            channels: cfg.audio.channels,
+            sample_rate: cfg.audio.sample_rate,
+            format: cfg.record.format,
+            directory: cfg.record.directory.clone()
+        };
+        std::thread::spawn(move || writer.run());
+        Self { producer, events, recording: false }
+    }
+
+    pub fn set(&mut self, on: bool) {
+        if on == self.recording {
+            return;
+        }
+        let event = if on { RecordEvent::Start } else { RecordEvent::Stop };
+        // the state only changes if the writer heard about it
+        if self.events.try_send(event).is_ok() {
+            self.recording = on;
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.set(!self.recording);
+    }
+
+    pub fn process(&mut self, buffer: &Block) {
+        if self.recording {
+            // if the writer falls behind the overflow is lost
+            self.producer.push_slice(buffer);
+        }
+    }
+}
+
+struct Writer {
+    consumer: Consumer<f32>,
+    events: Receiver<RecordEvent>,
+    channels: usize,
+    sample_rate: u32,
+    format: RecordFormat,
+    directory: String
+}
+impl Writer {
+    fn run(mut self) {
+        let mut buf = vec![0.0; 4096];
+        let mut file: Option<hound::WavWriter<std::io::BufWriter<fs::File>>> = None;
+        loop {
+            let event = self.events.recv_timeout(Duration::from_millis(50));
+            // everything pushed before a Stop is in the ring buffer by now
+            if let Some(writer) = file.as_mut() {
+                self.drain(writer, &mut buf);
+            }
+            match event {
+                Ok(RecordEvent::Start) => {
+                    file = self.create();
+                    if file.is_none() {
+                        // nowhere to keep what gets pushed until the Stop
+                        self.consumer.discard(self.consumer.len());
+                    }
+                }
+                Ok(RecordEvent::Stop) => {
+                    if let Some(writer) = file.take() {
+                        let len = writer.len();
+                        match writer.finalize() {
+                            Ok(_) => println!("Recorded {} samples", len),
+                            Err(e) => println!("Unable to finish recording: {}", e)
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => return
+            }
+        }
+    }
+
+    fn create(&self) -> Option<hound::WavWriter<std::io::BufWriter<fs::File>>> {
+        let (bits_per_sample, sample_format) = match self.format {
+            RecordFormat::Int24 => (24, hound::SampleFormat::Int),
+            RecordFormat::Float => (32, hound::SampleFormat::Float)
+        };
+        let spec = hound::WavSpec { channels: self.channels as u16, sample_rate: self.sample_rate, bits_per_sample, sample_format };
+        if let Err(e) = fs::create_dir_all(&self.directory) {
+            println!("Unable to create {}: {}", self.directory, e);
+            return None;
+        }
+        let path = Path::new(&self.directory).join(format!("recording-{}.wav", timestamp()));
+        match hound::WavWriter::create(&path, spec) {
+            Ok(writer) => {
+                println!("Recording to {}", path.display());
+                Some(writer)
+            }
+            Err(e) => {
+                println!("Unable to record to {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    fn drain(&mut self, writer: &mut hound::WavWriter<std::io::BufWriter<fs::File>>, buf: &mut [f32]) {
+        loop {
+            let n = self.consumer.pop_slice(buf);
+            if n == 0 {
+                return;
+            }
+            for s in buf[..n].iter() {
+                let result = match self.format {
+                    RecordFormat::Int24 => writer.write_sample((s.max(-1.0).min(1.0) * 8_388_607.0) as i32),
+                    RecordFormat::Float => writer.write_sample(*s)
+                };
+                if let Err(e) = result {
+                    println!("Recording write failed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// UTC date and time like 2021-03-04-150405, so recordings sort by name.
+fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rest) = ((secs / 86400) as i64, secs % 86400);
+    // days since 1970-01-01 to a civil date
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}-{:02}{:02}{:02}", year, month, day, rest / 3600, rest / 60 % 60, rest % 60)
+}
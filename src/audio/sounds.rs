@@ -1,69 +1,223 @@
 use crate::audio::parameters::Parameters;
+use crate::audio::Block;
 use crate::audio::AudioMessage;
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::pool::Sample;
+use crate::audio::envelope::Envelope;
+use crate::audio::voices::{self, Voices};
+use crate::audio::chain::Garbage;
+use crate::config::{Adsr, PlayMode, Sound};
+use crossbeam::channel::Sender;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+
+/// Upper bound on simultaneous sample voices, the oldest voice is stolen past this.
+const MAX_VOICES: usize = 64;
+/// Seconds a choked voice takes to fade out.
+const CHOKE_TIME: f32 = 0.005;
+
+/// Identifies what triggered a voice: the device, MIDI channel and note.
+/// The device key is kept as a hash so keys free nothing on the audio thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct VoiceKey {
+    pub device: u64,
+    pub channel: u8,
+    pub note: u8
+}
+impl VoiceKey {
+    pub fn new(device: &str, channel: u8, note: u8) -> Self {
+        let mut hasher = DefaultHasher::new();
+        device.hash(&mut hasher);
+        Self { device: hasher.finish(), channel, note }
+    }
+}
+
+/// How a voice plays its sample, taken from the `config::Sound` before it
+/// reaches the audio thread.
+#[derive(Debug, Clone, Copy)]
+pub struct Playback {
+    envelope: Adsr,
+    choke_group: Option<u32>,
+    mode: PlayMode,
+    loop_points: (usize, usize)
+}
+impl Playback {
+    pub fn new(sound: &Sound, sample: &Sample) -> Self {
+        Self { envelope: sound.envelope, choke_group: sound.choke_group, mode: sound.mode, loop_points: loop_points(sample, sound) }
+    }
+}
 
 pub struct SoundEntry {
-    id: u64,
-    buf: audrey::read::BufFileReader,
+    key: VoiceKey,
+    sample: Sample,
+    velocity: f32,
+    envelope: Envelope,
+    choke_group: Option<u32>,
+    mode: PlayMode,
+    loop_points: (usize, usize),
+    rate: f64,
+    position: f64,
     consumed: bool
 }
 impl SoundEntry {
-    fn process(&mut self, buffer: &mut nannou_audio::Buffer) {
-        let len_frames = buffer.len_frames();
-        let mut frame_count = 0;
-        let file_frames = self.buf.frames::<[f32; 2]>().filter_map(Result::ok);
-        for (frame, file_frame) in buffer.frames_mut().zip(file_frames) {
-            for (sample, file_sample) in frame.iter_mut().zip(&file_frame) {
-                *sample += *file_sample;// * sound_amp;
+    fn process(&mut self, buffer: &mut Block, gain: f32) {
+        let len_frames = self.sample.len_frames() as f64;
+        let (loop_start, loop_end) = (self.loop_points.0 as f64, self.loop_points.1 as f64);
+        for frame in buffer.frames_mut() {
+            // loops keep going through the release
+            if self.mode == PlayMode::Loop && self.position >= loop_end && loop_end > loop_start {
+                self.position = loop_start + (self.position - loop_end) % (loop_end - loop_start);
+            }
+            // Once the sample runs out of frames the sound has ended.
+            if self.position >= len_frames {
+                self.consumed = true;
+                break;
+            }
+            let amp = gain * self.velocity * self.envelope.next();
+            for (channel, sample) in frame.iter_mut().enumerate().take(self.sample.channels) {
+                *sample += self.sample.at(channel, self.position) * amp;
+            }
+            self.position += self.rate;
+            if self.envelope.is_idle() {
+                self.consumed = true;
+                break;
             }
-            frame_count += 1;
-        }
-        // If the sound yielded less samples than are in the buffer, it must have ended.
-        if frame_count < len_frames {
-            self.consumed = true;
         }
     }
 }
 
+impl voices::Voice for SoundEntry {
+    fn key(&self) -> Option<VoiceKey> {
+        Some(self.key)
+    }
+
+    fn release(&mut self) {
+        self.envelope.release();
+    }
+
+    fn is_released(&self) -> bool {
+        self.envelope.is_released()
+    }
+
+    fn is_done(&self) -> bool {
+        self.consumed
+    }
+
+    fn steal(&mut self) {
+        self.envelope.release_in(voices::STEAL_TIME);
+    }
+
+    fn garbage(self) -> Option<Garbage> {
+        Some(Garbage::Sample(self.sample))
+    }
+}
+
 pub struct Sounds {
     params: Parameters<f32>,
-    pub(crate) sounds: HashMap<u64, SoundEntry>
+    pub(crate) voices: Voices<SoundEntry>
 }
 impl Default for Sounds {
     fn default() -> Self {
-        Self { sounds: HashMap::new(), params: Parameters::default() }
+        Self { voices: Voices::new(MAX_VOICES), params: Parameters::default() }
     }
 }
 impl Sounds {
-    pub fn param(&mut self, key: &str, value: f32) {
-        self.params.update(key, &value);
+    /// Start a new voice for `key`, earlier voices with the same key keep playing.
+    pub fn on(&mut self, key: VoiceKey, sample: Sample, playback: Playback, velocity: f32, rate: f64) {
+        if let Some(group) = playback.choke_group {
+            self.choke(group);
+        }
+        let envelope = Envelope::new(playback.envelope, sample.sample_rate);
+        self.voices.start(SoundEntry {
+            key, sample, velocity, envelope,
+            choke_group: playback.choke_group,
+            mode: playback.mode,
+            loop_points: playback.loop_points,
+            rate,
+            position: 0.0,
+            consumed: false
+        });
+    }
+
+    /// Release the most recently started voice for `key`, it is removed once faded out.
+    /// One-shot voices ignore this and play to the end.
+    pub fn off(&mut self, key: &VoiceKey) {
+        let voice = self.voices.iter_mut().rev()
+            .find(|v| &v.key == key && v.mode != PlayMode::OneShot && !v.envelope.is_released());
+        if let Some(voice) = voice {
+            voice.envelope.release();
+        }
+    }
+
+    /// Quickly fade out every voice in the choke group.
+    fn choke(&mut self, group: u32) {
+        for voice in self.voices.iter_mut().filter(|v| v.choke_group == Some(group)) {
+            voice.envelope.release_in(CHOKE_TIME);
+        }
+    }
+
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of every voice", 0.0, 1.0, 1.0)
+];
+
+impl Processor for Sounds {
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
     }
 
-    pub fn on(&mut self, id: u64, buf: audrey::read::BufFileReader) {
-        self.sounds.insert(id, SoundEntry { id, buf, consumed: false });
+    fn param(&mut self, key: &str, value: f32) {
+        self.params.update(key, &value);
     }
 
-    pub fn off(&mut self, id: u64) {
-        self.sounds.remove(&id);
+    fn garbage(&mut self, garbage: &Sender<Garbage>) {
+        // a voice can hold the last of a sample replaced by a reload
+        self.voices.set_garbage(garbage);
     }
 
-    fn consume(&mut self, buffer: &mut nannou_audio::Buffer) -> Vec<u64> {
-        // Sum all of the sounds onto the buffer.
-        self.sounds.values_mut().filter(|sound| !sound.consumed).filter_map(|sound| {
-            sound.process(buffer);
-            if sound.consumed {
-                Some(sound.id)
-            } else {
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        match message {
+            AudioMessage::SoundOn { key, sample, playback, velocity, rate } => {
+                self.on(key, sample, playback, velocity, rate);
+                None
+            }
+            AudioMessage::SoundOff { key } => {
+                self.off(&key);
                 None
             }
-        }).collect::<Vec<_>>()
+            m => Some(m)
+        }
     }
 
-    pub fn process(&mut self, buffer: &mut nannou_audio::Buffer) {
-        for id in self.consume(buffer) {
-            self.sounds.remove(&id);
+    fn process(&mut self, buffer: &mut Block) {
+        let gain = self.params.get("A");
+        // Sum all of the sounds onto the buffer, dropping the ones that ended.
+        for voice in self.voices.iter_mut() {
+            voice.process(buffer, gain);
         }
+        self.voices.retain();
+    }
+
+    fn is_active(&self) -> bool {
+        !self.voices.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
     }
 }
 
+/// Loop points of the sound from the config, then the file, then the whole sample.
+fn loop_points(sample: &Sample, sound: &Sound) -> (usize, usize) {
+    let len_frames = sample.len_frames();
+    let (start, end) = match (sound.loop_start, sound.loop_end) {
+        (Some(start), Some(end)) => (sample.to_frames(start), sample.to_frames(end)),
+        (Some(start), None) => (sample.to_frames(start), len_frames),
+        (None, Some(end)) => (0, sample.to_frames(end)),
+        (None, None) => sample.loop_points.unwrap_or((0, len_frames))
+    };
+    let end = end.min(len_frames);
+    if start < end { (start, end) } else { (0, len_frames) }
+}
@@ -0,0 +1,195 @@
+use crate::audio::parameters::Parameters;
+use crate::audio::{AudioMessage, Block, VoiceKey};
+use crate::audio::processor::{ParamDescriptor, Processor};
+use crate::audio::envelope::Envelope;
+use crate::audio::nodes::{self, Frame};
+use crate::audio::voices::{self, Note, Voices};
+use crate::config::{Adsr, FilterMode, Wave};
+
+/// Upper bound on simultaneous synth voices, the oldest voice is stolen past this.
+const MAX_VOICES: usize = 16;
+/// Frames the filter cutoff is held for between filter envelope steps.
+const CHUNK: usize = 32;
+
+/// Two detuned oscillators through a resonant lowpass and an amp envelope,
+/// with a second envelope sweeping the cutoff.
+struct Voice {
+    key: VoiceKey,
+    velocity: f32,
+    oscillators: [nodes::Oscillator; 2],
+    filter: nodes::Filter,
+    amp: Envelope,
+    filter_env: Envelope,
+    done: bool
+}
+impl Voice {
+    fn process(&mut self, buffer: &mut Block, params: &Parameters<f32>, bend: f32) {
+        let sample_hz = buffer.sample_rate() as f64;
+        let note = self.key.note as f32 + bend * params.get("bend");
+        let freq = 440.0 * 2f32.powf((note - 69.0) / 12.0);
+        let detune = 2f32.powf(params.get("detune") / 1200.0);
+        let gain = params.get("A") * self.velocity;
+        for (i, osc) in self.oscillators.iter_mut().enumerate() {
+            osc.set("freq", if i == 0 { freq } else { freq * detune });
+            osc.set("amp", 0.5);
+            osc.set("width", params.get("width"));
+        }
+        self.filter.set("q", params.get("q"));
+
+        let mut remaining = buffer.len_frames();
+        let mut frames = buffer.frames_mut();
+        let mut out: [Frame; CHUNK] = [[0.0; 2]; CHUNK];
+        let mut other: [Frame; CHUNK] = [[0.0; 2]; CHUNK];
+        while remaining > 0 && !self.done {
+            let n = remaining.min(CHUNK);
+            remaining -= n;
+            let (out, other) = (&mut out[..n], &mut other[..n]);
+            self.oscillators[0].process(out, sample_hz);
+            self.oscillators[1].process(other, sample_hz);
+            for (o, s) in out.iter_mut().zip(other.iter()) {
+                *o = [o[0] + s[0], o[1] + s[1]];
+            }
+
+            let mut sweep = 0.0;
+            for _ in 0..n {
+                sweep = self.filter_env.next();
+            }
+            let cutoff = params.get("cutoff") * 2f32.powf(sweep * params.get("env"));
+            self.filter.set("cutoff", cutoff);
+            self.filter.process(out, sample_hz);
+
+            for (frame, v) in (&mut frames).take(n).zip(out.iter()) {
+                let amp = gain * self.amp.next();
+                for (sample, v) in frame.iter_mut().zip(v.iter()) {
+                    *sample += v * amp;
+                }
+                if self.amp.is_idle() {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl voices::Voice for Voice {
+    fn key(&self) -> Option<VoiceKey> {
+        Some(self.key)
+    }
+
+    fn release(&mut self) {
+        self.amp.release();
+        self.filter_env.release();
+    }
+
+    fn is_released(&self) -> bool {
+        self.amp.is_released()
+    }
+
+    fn is_done(&self) -> bool {
+        self.done
+    }
+
+    fn steal(&mut self) {
+        self.amp.release_in(voices::STEAL_TIME);
+    }
+}
+
+/// A polyphonic subtractive synth played from devices mapped to it by name,
+/// like `{ synth = "lead" }`.
+pub struct Synth {
+    params: Parameters<f32>,
+    sample_rate: u32,
+    voices: Voices<Voice>
+}
+impl Default for Synth {
+    fn default() -> Self {
+        Self { params: Parameters::default(), sample_rate: 44100, voices: Voices::new(MAX_VOICES) }
+    }
+}
+impl Synth {
+    fn on(&mut self, key: VoiceKey, velocity: f32) {
+        self.voices.release(&key);
+        let p = &self.params;
+        let wave = wave(p.get("wave"));
+        let amp = Adsr { attack: p.get("attack"), hold: 0.0, decay: p.get("decay"), sustain: p.get("sustain"), release: p.get("release") };
+        let filter = Adsr { attack: p.get("f_attack"), hold: 0.0, decay: p.get("f_decay"), sustain: p.get("f_sustain"), release: p.get("f_release") };
+        self.voices.start(Voice {
+            key,
+            velocity,
+            oscillators: [nodes::Oscillator::new(wave), nodes::Oscillator::new(wave)],
+            filter: nodes::Filter::new(FilterMode::Lowpass),
+            amp: Envelope::new(amp, self.sample_rate),
+            filter_env: Envelope::new(filter, self.sample_rate),
+            done: false
+        });
+    }
+}
+
+/// The wave picked by the `wave` param, taken when a note starts.
+fn wave(value: f32) -> Wave {
+    match value.round() as i32 {
+        0 => Wave::Sine,
+        2 => Wave::Pulse,
+        3 => Wave::Triangle,
+        _ => Wave::Saw
+    }
+}
+
+const PARAMS: &[ParamDescriptor] = &[
+    ParamDescriptor::new("A", "gain of every voice", 0.0, 1.0, 0.5),
+    ParamDescriptor::new("wave", "0 sine, 1 saw, 2 pulse, 3 triangle", 0.0, 3.0, 1.0),
+    ParamDescriptor::new("width", "part of the cycle the pulse is high for", 0.01, 0.99, 0.5),
+    ParamDescriptor::new("detune", "cents the second oscillator is tuned up", 0.0, 100.0, 7.0),
+    ParamDescriptor::new("cutoff", "lowpass cutoff in Hz before the filter envelope", 20.0, 20000.0, 800.0),
+    ParamDescriptor::new("q", "resonance of the lowpass", 0.1, 20.0, 2.0),
+    ParamDescriptor::new("env", "octaves the filter envelope raises the cutoff", -8.0, 8.0, 3.0),
+    ParamDescriptor::new("attack", "seconds of the amp attack", 0.0, 10.0, 0.005),
+    ParamDescriptor::new("decay", "seconds of the amp decay", 0.0, 10.0, 0.2),
+    ParamDescriptor::new("sustain", "level of the amp sustain", 0.0, 1.0, 0.7),
+    ParamDescriptor::new("release", "seconds of the amp release", 0.0, 10.0, 0.3),
+    ParamDescriptor::new("f_attack", "seconds of the filter attack", 0.0, 10.0, 0.005),
+    ParamDescriptor::new("f_decay", "seconds of the filter decay", 0.0, 10.0, 0.4),
+    ParamDescriptor::new("f_sustain", "level of the filter sustain", 0.0, 1.0, 0.2),
+    ParamDescriptor::new("f_release", "seconds of the filter release", 0.0, 10.0, 0.3),
+    ParamDescriptor::new("bend", "semitones of a full pitch bend", 0.0, 24.0, 2.0)
+];
+
+impl Processor for Synth {
+    fn prepare(&mut self, sample_rate: u32, _block_size: usize) {
+        self.sample_rate = sample_rate;
+    }
+
+    fn params(&self) -> &'static [ParamDescriptor] {
+        PARAMS
+    }
+
+    fn param(&mut self, key: &str, value: f32) {
+        self.params.update(key, &value);
+    }
+
+    fn message(&mut self, message: AudioMessage) -> Option<AudioMessage> {
+        match self.voices.route(message) {
+            Note::On { key, velocity } => self.on(key, velocity),
+            Note::Off { key } => self.voices.release(&key),
+            Note::Bend => (),
+            Note::Other(m) => return Some(m)
+        }
+        None
+    }
+
+    fn process(&mut self, buffer: &mut Block) {
+        for (voice, bend) in self.voices.iter_bent() {
+            voice.process(buffer, &self.params, bend);
+        }
+        self.voices.retain();
+    }
+
+    fn is_active(&self) -> bool {
+        !self.voices.is_empty()
+    }
+
+    fn reset(&mut self) {
+        self.voices.clear();
+    }
+}
@@ -0,0 +1,205 @@
+use crossbeam::channel::Sender;
+use std::ops::{Deref, DerefMut};
+
+use crate::audio::{AudioMessage, VoiceKey};
+use crate::audio::chain::{self, Garbage};
+
+/// Seconds a stolen voice takes to fade out.
+pub const STEAL_TIME: f32 = 0.005;
+
+/// A voice of one of the engines, like a synth note or a drum hit.
+pub trait Voice {
+    /// What started it, drum hits and drones have no key.
+    fn key(&self) -> Option<VoiceKey>;
+    /// Let go of the note, the voice rings out before it is done.
+    fn release(&mut self);
+    fn is_released(&self) -> bool;
+    fn is_done(&self) -> bool;
+    /// Fade out over `STEAL_TIME`, a new voice is taking its place.
+    fn steal(&mut self);
+    /// What is left to free once the voice is done, voices owning no memory
+    /// hand nothing over.
+    fn garbage(self) -> Option<Garbage> where Self: Sized {
+        None
+    }
+}
+
+/// What a message means to a polyphonic processor, see `Voices::route`.
+pub enum Note {
+    On { key: VoiceKey, velocity: f32 },
+    Off { key: VoiceKey },
+    /// a pitch bend, already kept by `Voices`
+    Bend,
+    /// any other message, handed back
+    Other(AudioMessage)
+}
+
+/// The voices of a processor, with the pitch bend of each MIDI channel.
+pub struct Voices<V> {
+    voices: Vec<V>,
+    max: usize,
+    // how many of the first voices are fading out since they were stolen
+    stolen: usize,
+    // between -1 and 1
+    bend: [f32; 16],
+    // where the voices that are done go, see `set_garbage`
+    garbage: Option<Sender<Garbage>>
+}
+impl<V: Voice> Voices<V> {
+    /// Room for `max` voices, and as many again fading out once stolen, is
+    /// allocated up front.
+    pub fn new(max: usize) -> Self {
+        Self { voices: Vec::with_capacity(max * 2), max, stolen: 0, bend: [0.0; 16], garbage: None }
+    }
+
+    /// Hand the voices that are done to the drop thread from now on.
+    pub fn set_garbage(&mut self, garbage: &Sender<Garbage>) {
+        if self.garbage.is_none() {
+            self.garbage = Some(garbage.clone());
+        }
+    }
+
+    /// Past `max` voices the oldest one left is stolen, it fades out quickly
+    /// rather than stopping with a click.
+    pub fn start(&mut self, voice: V) {
+        // voices are kept in start order, so the stolen ones come first
+        if self.voices.len() - self.stolen >= self.max {
+            self.voices[self.stolen].steal();
+            self.stolen += 1;
+        }
+        // stolen faster than they fade out, the oldest is cut after all
+        if self.voices.len() >= self.max * 2 {
+            let voice = self.voices.remove(0);
+            self.stolen -= 1;
+            discard(self.garbage.as_ref(), voice);
+        }
+        self.voices.push(voice);
+    }
+
+    /// Release every voice of `key` still held.
+    pub fn release(&mut self, key: &VoiceKey) {
+        for voice in self.voices.iter_mut().filter(|v| v.key().as_ref() == Some(key) && !v.is_released()) {
+            voice.release();
+        }
+    }
+
+    /// Drop the voices that are done.
+    pub fn retain(&mut self) {
+        let mut i = 0;
+        while i < self.voices.len() {
+            if self.voices[i].is_done() {
+                let voice = self.voices.remove(i);
+                if i < self.stolen {
+                    self.stolen -= 1;
+                }
+                discard(self.garbage.as_ref(), voice);
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Drop every voice and bend.
+    pub fn clear(&mut self) {
+        for voice in self.voices.drain(..) {
+            discard(self.garbage.as_ref(), voice);
+        }
+        self.stolen = 0;
+        self.bend = [0.0; 16];
+    }
+
+    /// Each voice with the bend of its channel.
+    pub fn iter_bent(&mut self) -> impl Iterator<Item=(&mut V, f32)> {
+        let bend = &self.bend;
+        self.voices.iter_mut().map(move |v| {
+            let b = v.key().map_or(0.0, |k| bend[k.channel as usize % 16]);
+            (v, b)
+        })
+    }
+
+    /// Sort out the notes and bends of a message.
+    pub fn route(&mut self, message: AudioMessage) -> Note {
+        match message {
+            AudioMessage::NoteOn { key, velocity, .. } => Note::On { key, velocity },
+            AudioMessage::NoteOff { key, .. } => Note::Off { key },
+            AudioMessage::PitchBend { channel, bend, .. } => {
+                self.bend[channel as usize % 16] = bend;
+                Note::Bend
+            }
+            m => Note::Other(m)
+        }
+    }
+}
+/// Hand what `voice` leaves to the drop thread, without one it is freed here.
+fn discard<V: Voice>(garbage: Option<&Sender<Garbage>>, voice: V) {
+    if let (Some(tx), Some(g)) = (garbage, voice.garbage()) {
+        chain::discard(tx, g);
+    }
+}
+
+impl<V> Deref for Voices<V> {
+    type Target = [V];
+    fn deref(&self) -> &[V] {
+        &self.voices
+    }
+}
+impl<V> DerefMut for Voices<V> {
+    fn deref_mut(&mut self) -> &mut [V] {
+        &mut self.voices
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Held {
+        note: u8,
+        stolen: bool,
+        done: bool
+    }
+    impl Voice for Held {
+        fn key(&self) -> Option<VoiceKey> {
+            Some(VoiceKey::new("test", 0, self.note))
+        }
+
+        fn release(&mut self) {}
+
+        fn is_released(&self) -> bool {
+            self.stolen
+        }
+
+        fn is_done(&self) -> bool {
+            self.done
+        }
+
+        fn steal(&mut self) {
+            self.stolen = true;
+        }
+    }
+
+    fn start(voices: &mut Voices<Held>, note: u8) {
+        voices.start(Held { note, stolen: false, done: false });
+    }
+
+    #[test]
+    fn stolen_voices_fade_out() {
+        let mut voices = Voices::new(2);
+        (0..3).for_each(|n| start(&mut voices, n));
+        // the oldest keeps playing until it has faded out
+        assert_eq!(voices.len(), 3);
+        assert!(voices[0].stolen && !voices[1].stolen);
+        voices[0].done = true;
+        voices.retain();
+        start(&mut voices, 3);
+        assert_eq!(voices.iter().map(|v| (v.note, v.stolen)).collect::<Vec<_>>(), vec![(1, true), (2, false), (3, false)]);
+    }
+
+    #[test]
+    fn stolen_too_fast_are_cut() {
+        let mut voices = Voices::new(2);
+        (0..6).for_each(|n| start(&mut voices, n));
+        assert_eq!(voices.len(), 4);
+        assert_eq!(voices.iter().map(|v| (v.note, v.stolen)).collect::<Vec<_>>(), vec![(2, true), (3, true), (4, false), (5, false)]);
+    }
+}
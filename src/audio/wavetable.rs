@@ -0,0 +1,206 @@
+use crate::audio::nodes::Frame;
+
+/// Samples in each frame of a table, frames of other sizes are resampled to it.
+const TABLE_SIZE: usize = 2048;
+/// One table per octave, each with half the harmonics of the one before.
+const LEVELS: usize = 11;
+
+/// Frames of one cycle each, as many times as there are mipmap levels.
+#[derive(Debug)]
+pub struct Tables {
+    // levels[level][frame], level 0 has every harmonic
+    levels: Vec<Vec<Vec<f32>>>
+}
+impl Tables {
+    /// Cut a wav file into frames of `frame_size` samples, a file shorter than
+    /// that is taken as a single cycle. Only the first channel is used.
+    pub fn load(path: &str, frame_size: usize) -> Option<Tables> {
+        let mut reader = match audrey::open(path) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Unable to load wavetable {}: {:?}", path, e);
+                return None;
+            }
+        };
+        let channels = reader.description().channel_count() as usize;
+        let data: Vec<f32> = reader.samples::<f32>().filter_map(Result::ok).step_by(channels.max(1)).collect();
+        if data.is_empty() {
+            println!("Wavetable {} is empty", path);
+            return None;
+        }
+        let frames: Vec<Vec<f32>> = if data.len() < frame_size {
+            vec![resize(&data)]
+        } else {
+            data.chunks_exact(frame_size.max(1)).map(resize).collect()
+        };
+        println!("Loaded wavetable {} with {} frames", path, frames.len());
+        Some(Tables::new(frames))
+    }
+
+    pub fn sine() -> Tables {
+        let frame = (0..TABLE_SIZE).map(|i| (i as f32 / TABLE_SIZE as f32 * 2.0 * std::f32::consts::PI).sin()).collect();
+        Tables::new(vec![frame])
+    }
+
+    fn new(frames: Vec<Vec<f32>>) -> Tables {
+        let mut levels = vec![frames.clone()];
+        for level in 1..LEVELS {
+            let harmonics = TABLE_SIZE / 2 >> level;
+            levels.push(frames.iter().map(|f| band_limit(f, harmonics)).collect());
+        }
+        Tables { levels }
+    }
+
+    fn frames(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    /// The level whose highest harmonic stays under the Nyquist frequency.
+    fn level(&self, freq: f64, sample_hz: f64) -> usize {
+        let harmonics = sample_hz / 2.0 / freq.max(1.0);
+        (0..LEVELS).find(|l| (TABLE_SIZE / 2 >> l) as f64 <= harmonics).unwrap_or(LEVELS - 1)
+    }
+}
+
+/// Replaces its inputs with a cycle read from the tables, `position` morphs
+/// from the first frame at 0 to the last at 1.
+#[derive(Debug)]
+pub struct Wavetable {
+    tables: Tables,
+    phase: f64,
+    freq: f32,
+    amp: f32,
+    position: f32
+}
+impl Wavetable {
+    pub fn new(tables: Tables) -> Self {
+        Self { tables, phase: 0.0, freq: 440.0, amp: 1.0, position: 0.0 }
+    }
+
+    pub fn set(&mut self, param: &str, value: f32) {
+        match param {
+            "freq" => self.freq = value,
+            "amp" => self.amp = value,
+            "position" => self.position = value,
+            _ => ()
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
+    pub fn process(&mut self, buffer: &mut [Frame], sample_hz: f64) {
+        let step = (self.freq as f64 / sample_hz).max(0.0).min(0.5);
+        let frames = &self.tables.levels[self.tables.level(self.freq as f64, sample_hz)];
+        let position = self.position.max(0.0).min(1.0) * (frames.len() - 1) as f32;
+        let a = &frames[position as usize];
+        let b = &frames[(position as usize + 1).min(self.tables.frames() - 1)];
+        let morph = position.fract();
+        for frame in buffer.iter_mut() {
+            let x = self.phase * TABLE_SIZE as f64;
+            let i = x as usize % TABLE_SIZE;
+            let j = (i + 1) % TABLE_SIZE;
+            let t = x.fract() as f32;
+            let va = a[i] + (a[j] - a[i]) * t;
+            let vb = b[i] + (b[j] - b[i]) * t;
+            let v = (va + (vb - va) * morph) * self.amp;
+            *frame = [v, v];
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+/// Stretch a cycle to `TABLE_SIZE` samples by linear interpolation.
+fn resize(cycle: &[f32]) -> Vec<f32> {
+    (0..TABLE_SIZE).map(|i| {
+        let x = i as f32 * cycle.len() as f32 / TABLE_SIZE as f32;
+        let j = x as usize;
+        let next = cycle[(j + 1) % cycle.len()];
+        cycle[j] + (next - cycle[j]) * x.fract()
+    }).collect()
+}
+
+/// The cycle without the harmonics above `harmonics`.
+fn band_limit(cycle: &[f32], harmonics: usize) -> Vec<f32> {
+    let mut re: Vec<f32> = cycle.to_vec();
+    let mut im = vec![0.0; cycle.len()];
+    fft(&mut re, &mut im, false);
+    let n = cycle.len();
+    for k in harmonics + 1..=n - harmonics - 1 {
+        re[k] = 0.0;
+        im[k] = 0.0;
+    }
+    fft(&mut re, &mut im, true);
+    re
+}
+
+/// In place radix 2 FFT, the length must be a power of two. The inverse is scaled by 1/n.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * 2.0 * std::f64::consts::PI / len as f64;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (start + k, start + k + len / 2);
+                let tr = re[b] * cos as f32 - im[b] * sin as f32;
+                let ti = re[b] * sin as f32 + im[b] * cos as f32;
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        len <<= 1;
+    }
+    if inverse {
+        re.iter_mut().for_each(|x| *x /= n as f32);
+        im.iter_mut().for_each(|x| *x /= n as f32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(harmonic: usize, n: usize) -> Vec<f32> {
+        (0..n).map(|i| (i as f32 * harmonic as f32 / n as f32 * 2.0 * std::f32::consts::PI).sin()).collect()
+    }
+
+    #[test]
+    fn fft_round_trip() {
+        let cycle = sine(3, 64);
+        let (mut re, mut im) = (cycle.clone(), vec![0.0; 64]);
+        fft(&mut re, &mut im, false);
+        // all of a sine is in its own bin and the mirror of it
+        assert!((im[3] + 32.0).abs() < 1e-3);
+        assert!((im[61] - 32.0).abs() < 1e-3);
+        assert!(re.iter().chain(im.iter()).map(|x| x.abs()).sum::<f32>() < 64.0 + 1e-2);
+        fft(&mut re, &mut im, true);
+        assert!(re.iter().zip(cycle.iter()).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn band_limit_drops_high_harmonics() {
+        let (low, high) = (sine(2, 64), sine(20, 64));
+        let mixed: Vec<f32> = low.iter().zip(high.iter()).map(|(a, b)| a + b).collect();
+        let limited = band_limit(&mixed, 8);
+        assert!(limited.iter().zip(low.iter()).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+}
@@ -1,20 +1,28 @@
 use serde_derive::Deserialize;
 use std::fs;
+use std::path::PathBuf;
 use std::collections::{HashMap, HashSet};
-use notify::{Watcher, DebouncedEvent, RecursiveMode, watcher};
+use notify::{Watcher, DebouncedEvent, RecommendedWatcher, RecursiveMode, watcher};
 use std::sync::mpsc::channel;
 use std::time::Duration;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use nannou::rand::random_range;
 use super::config;
 use super::midi;
 use super::message;
+use super::audio::pool::SamplePool;
+use super::sfz;
+use super::sf2;
 
 #[derive(Deserialize, Debug, PartialEq, Clone)]
 pub enum DeviceType {
     #[serde(rename="hardware")]
     Hardware,
     #[serde(rename="virtual")]
-    Virtual
+    Virtual,
+    /// an audio interface, inputs are mixed into the output
+    #[serde(rename="audio")]
+    Audio
 }
 impl Default for DeviceType {
     fn default() -> Self {
@@ -22,6 +30,34 @@ impl Default for DeviceType {
     }
 }
 
+/// How a NoteOn velocity maps to the gain of the voice.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum VelocityCurve {
+    #[serde(rename="linear")]
+    Linear,
+    /// 40dB of range, closer to how loudness is heard
+    #[serde(rename="exponential")]
+    Exponential,
+    /// ignore velocity and always play at full volume
+    #[serde(rename="fixed")]
+    Fixed
+}
+impl Default for VelocityCurve {
+    fn default() -> Self {
+        Self::Linear
+    }
+}
+impl VelocityCurve {
+    pub fn gain(&self, velocity: u8) -> f32 {
+        let v = velocity.min(127) as f32 / 127.0;
+        match self {
+            Self::Linear => v,
+            Self::Exponential => if velocity == 0 { 0.0 } else { 10f32.powf(2.0 * (v - 1.0)) },
+            Self::Fixed => 1.0
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Device {
     pub key: String,
@@ -34,7 +70,16 @@ pub struct Device {
     pub input: bool,
     #[serde(default="default_false")]
     pub output: bool,
-    pub mapping: Option<Vec<DeviceMap>>
+    pub velocity: Option<VelocityCurve>,
+    pub mapping: Option<Vec<DeviceMap>>,
+    /// input channels of an audio device to use, counting from 1, all of them if not set
+    pub channels: Option<Vec<usize>>,
+    /// gain of an audio device input
+    #[serde(default="default_gain")]
+    pub gain: f32,
+    /// hear an audio device input, it can still be sampled when off
+    #[serde(default="default_true")]
+    pub monitor: bool
 }
 impl Device {
     pub fn mappings(&self) -> Vec<ParsedDeviceMap> {
@@ -53,7 +98,493 @@ pub struct Sound {
     pub description: String,
     #[serde(default="default_seq")]
     pub seq: u8,
-    pub path: String
+    #[serde(default="empty_string")]
+    pub path: String,
+    /// an sfz instrument, each of its regions is loaded as a sound under this key
+    pub sfz: Option<String>,
+    /// a soundfont, each preset is loaded under `key/bank/program`
+    pub sf2: Option<String>,
+    /// overrides the velocity curve of the device
+    pub velocity: Option<VelocityCurve>,
+    /// notes the sound plays for
+    #[serde(default="default_lokey")]
+    pub lokey: u8,
+    #[serde(default="default_hikey")]
+    pub hikey: u8,
+    /// note the sample was recorded at, it is repitched from here
+    pub root: Option<u8>,
+    /// velocity range of the layer when the key is selected by velocity
+    #[serde(default="default_lovel")]
+    pub lovel: u8,
+    #[serde(default="default_hivel")]
+    pub hivel: u8,
+    /// starting this sound fades out every other voice in the same group,
+    /// like a closed hi-hat cutting off an open one
+    pub choke_group: Option<u32>,
+    #[serde(default="PlayMode::default")]
+    pub mode: PlayMode,
+    /// loop points in frames of the file, the smpl chunk of the file is used when not set
+    pub loop_start: Option<usize>,
+    pub loop_end: Option<usize>,
+    #[serde(flatten)]
+    pub envelope: Adsr
+}
+impl Default for Sound {
+    fn default() -> Self {
+        Self {
+            key: empty_string(),
+            description: empty_string(),
+            seq: default_seq(),
+            path: empty_string(),
+            sfz: None,
+            sf2: None,
+            velocity: None,
+            lokey: default_lokey(),
+            hikey: default_hikey(),
+            root: None,
+            lovel: default_lovel(),
+            hivel: default_hivel(),
+            choke_group: None,
+            mode: PlayMode::default(),
+            loop_start: None,
+            loop_end: None,
+            envelope: Adsr::default()
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum PlayMode {
+    /// plays to the end, NoteOff is ignored
+    #[serde(rename="one-shot")]
+    OneShot,
+    /// NoteOff releases the sound
+    #[serde(rename="gate")]
+    Gate,
+    /// repeats the loop until NoteOff releases it
+    #[serde(rename="loop")]
+    Loop
+}
+impl Default for PlayMode {
+    fn default() -> Self {
+        Self::Gate
+    }
+}
+
+/// Amplitude envelope, times in seconds and sustain as a level between 0 and 1.
+/// The short default attack and release keep voices from clicking.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub struct Adsr {
+    #[serde(default="default_attack")]
+    pub attack: f32,
+    #[serde(default="default_zero")]
+    pub hold: f32,
+    #[serde(default="default_zero")]
+    pub decay: f32,
+    #[serde(default="default_sustain")]
+    pub sustain: f32,
+    #[serde(default="default_release")]
+    pub release: f32
+}
+impl Default for Adsr {
+    fn default() -> Self {
+        Self { attack: default_attack(), hold: 0.0, decay: 0.0, sustain: default_sustain(), release: default_release() }
+    }
+}
+
+/// How a key picks between its sounds each time it is triggered.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum SelectionMode {
+    /// always the sound with the lowest seq
+    #[serde(rename="first")]
+    First,
+    #[serde(rename="round-robin")]
+    RoundRobin,
+    #[serde(rename="random")]
+    Random,
+    /// random, but never the same sound twice in a row
+    #[serde(rename="random-no-repeat")]
+    RandomNoRepeat,
+    /// the sounds whose lovel/hivel contain the velocity, round-robin between them
+    #[serde(rename="velocity")]
+    Velocity
+}
+impl Default for SelectionMode {
+    fn default() -> Self {
+        Self::First
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Selection {
+    pub key: String,
+    pub mode: SelectionMode
+}
+
+#[derive(Debug, Clone, Default)]
+struct SelectionState {
+    count: usize,
+    last: Option<usize>
+}
+
+/// Format of the output stream, sounds are converted to it when loaded.
+/// The stream is only opened at startup so changes here need a restart.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AudioConfig {
+    #[serde(default="default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default="default_channels")]
+    pub channels: usize
+}
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self { sample_rate: default_sample_rate(), channels: default_channels() }
+    }
+}
+
+/// Sample format of recordings of the master output.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum RecordFormat {
+    #[serde(rename="24-bit")]
+    Int24,
+    #[serde(rename="float")]
+    Float
+}
+impl Default for RecordFormat {
+    fn default() -> Self {
+        Self::Int24
+    }
+}
+
+/// Where and how recordings are written, read when the output stream opens.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordConfig {
+    #[serde(default="RecordFormat::default")]
+    pub format: RecordFormat,
+    #[serde(default="default_record_directory")]
+    pub directory: String
+}
+impl Default for RecordConfig {
+    fn default() -> Self {
+        Self { format: RecordFormat::default(), directory: default_record_directory() }
+    }
+}
+
+/// A processor param set to a constant or following a signal.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(untagged)]
+pub enum ParamValue {
+    Value(f32),
+    Signal(String)
+}
+
+/// A stage of the audio chain, run in the order they are listed.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct ProcessorConfig {
+    /// sounds, drums, dasp_test, general, synth, fm, granular, pluck or mallet
+    #[serde(rename="type")]
+    pub kind: String,
+    /// tells apart processors of the same type, defaults to the type
+    pub name: Option<String>,
+    #[serde(default="default_false")]
+    pub bypass: bool,
+    #[serde(default="HashMap::new")]
+    pub params: HashMap<String, ParamValue>,
+    /// patch file the dasp_test graph is built from
+    pub patch: Option<String>,
+    /// sound key the granular processor plays
+    pub sound: Option<String>
+}
+impl ProcessorConfig {
+    pub fn name(&self) -> &str {
+        self.name.as_ref().unwrap_or(&self.kind)
+    }
+
+    fn new(kind: &str, params: &[(&str, ParamValue)]) -> Self {
+        let params = params.iter().map(|(k, v)| (k.to_string(), v.clone())).collect();
+        Self { kind: kind.to_string(), name: None, bypass: false, params, patch: None, sound: None }
+    }
+}
+
+/// Drums the drums processor synthesizes, mappings play them with a sound
+/// key like `drum:kick` in place of a sound from sounds.toml.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Drum {
+    Kick,
+    Snare,
+    Hat,
+    OpenHat,
+    Clap,
+    Tom
+}
+impl Drum {
+    pub fn from_key(key: &str) -> Option<Drum> {
+        match key.strip_prefix("drum:")? {
+            "kick" => Some(Drum::Kick),
+            "snare" => Some(Drum::Snare),
+            "hat" => Some(Drum::Hat),
+            "openhat" => Some(Drum::OpenHat),
+            "clap" => Some(Drum::Clap),
+            "tom" => Some(Drum::Tom),
+            _ => None
+        }
+    }
+}
+
+/// Waveform of a patch oscillator.
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum Wave {
+    #[serde(rename="sine")]
+    Sine,
+    #[serde(rename="saw")]
+    Saw,
+    /// square at a width of 0.5
+    #[serde(rename="pulse")]
+    Pulse,
+    #[serde(rename="triangle")]
+    Triangle
+}
+impl Default for Wave {
+    fn default() -> Self {
+        Self::Sine
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum FilterMode {
+    #[serde(rename="lowpass")]
+    Lowpass,
+    #[serde(rename="highpass")]
+    Highpass,
+    #[serde(rename="bandpass")]
+    Bandpass
+}
+impl Default for FilterMode {
+    fn default() -> Self {
+        Self::Lowpass
+    }
+}
+
+/// What a patch node does to the sum of its inputs. Params are numbers or
+/// the name of a signal.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+#[serde(tag="type")]
+pub enum NodeKind {
+    /// replaces its inputs with a waveform
+    #[serde(rename="oscillator")]
+    Oscillator {
+        #[serde(default="Wave::default")]
+        wave: Wave,
+        #[serde(default="default_freq")]
+        freq: ParamValue,
+        #[serde(default="default_unity")]
+        amp: ParamValue,
+        /// part of the cycle a pulse is high for
+        #[serde(default="default_width")]
+        width: ParamValue
+    },
+    /// cycles read from a wav file, replaces its inputs. The file is cut into
+    /// frames of `frame_size` samples that `position` morphs between, 0 to 1
+    #[serde(rename="wavetable")]
+    Wavetable {
+        path: String,
+        #[serde(default="default_frame_size")]
+        frame_size: usize,
+        #[serde(default="default_freq")]
+        freq: ParamValue,
+        #[serde(default="default_unity")]
+        amp: ParamValue,
+        #[serde(default="default_off")]
+        position: ParamValue
+    },
+    /// white noise, replaces its inputs
+    #[serde(rename="noise")]
+    Noise {
+        #[serde(default="default_unity")]
+        amp: ParamValue
+    },
+    /// resonant two pole filter
+    #[serde(rename="filter")]
+    Filter {
+        #[serde(default="FilterMode::default")]
+        mode: FilterMode,
+        #[serde(default="default_cutoff")]
+        cutoff: ParamValue,
+        #[serde(default="default_q")]
+        q: ParamValue
+    },
+    /// shapes its inputs, opened while the gate is above 0.5
+    #[serde(rename="envelope")]
+    Envelope {
+        gate: ParamValue,
+        #[serde(default="default_attack")]
+        attack: f32,
+        #[serde(default="default_zero")]
+        hold: f32,
+        #[serde(default="default_zero")]
+        decay: f32,
+        #[serde(default="default_sustain")]
+        sustain: f32,
+        #[serde(default="default_release")]
+        release: f32
+    },
+    /// four operator FM voice, replaces its inputs and plays while the gate is
+    /// above 0.5, each list has an entry per operator
+    #[serde(rename="fm")]
+    Fm {
+        gate: ParamValue,
+        #[serde(default="default_freq")]
+        freq: ParamValue,
+        /// 1 to 8, how the operators modulate each other
+        #[serde(default="default_unity")]
+        algorithm: ParamValue,
+        #[serde(default="default_off")]
+        feedback: ParamValue,
+        /// frequency of each operator relative to freq
+        #[serde(default="default_ratios")]
+        ratio: Vec<ParamValue>,
+        #[serde(default="default_levels")]
+        level: Vec<ParamValue>,
+        #[serde(default="Vec::new")]
+        envelope: Vec<Adsr>
+    },
+    /// sums its inputs
+    #[serde(rename="mixer")]
+    Mixer {
+        #[serde(default="default_unity")]
+        gain: ParamValue
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct NodeConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: NodeKind
+}
+
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct ConnectionConfig {
+    pub from: String,
+    pub to: String
+}
+
+/// A DSP graph declared in a patch file like patch.toml.
+#[derive(Deserialize, Debug, PartialEq, Clone)]
+pub struct Patch {
+    /// the node heard at the end of the graph
+    pub output: String,
+    #[serde(default="Vec::new")]
+    pub node: Vec<NodeConfig>,
+    #[serde(default="Vec::new")]
+    pub connection: Vec<ConnectionConfig>
+}
+impl Patch {
+    pub fn load(filename: &str) -> Option<Patch> {
+        let s = match fs::read_to_string(filename) {
+            Ok(s) => s,
+            Err(_) => {
+                println!("Unable to read patch {}", filename);
+                return None;
+            }
+        };
+        match toml::from_str(&s) {
+            Ok(patch) => Some(patch),
+            Err(e) => {
+                println!("Unable to parse patch {}: {}", filename, e);
+                None
+            }
+        }
+    }
+}
+
+fn default_freq() -> ParamValue {
+    ParamValue::Value(440.0)
+}
+
+fn default_unity() -> ParamValue {
+    ParamValue::Value(1.0)
+}
+
+fn default_off() -> ParamValue {
+    ParamValue::Value(0.0)
+}
+
+fn default_frame_size() -> usize {
+    2048
+}
+
+fn default_ratios() -> Vec<ParamValue> {
+    vec![ParamValue::Value(1.0); 4]
+}
+
+fn default_levels() -> Vec<ParamValue> {
+    vec![ParamValue::Value(0.5), ParamValue::Value(0.5), ParamValue::Value(0.5), ParamValue::Value(1.0)]
+}
+
+fn default_width() -> ParamValue {
+    ParamValue::Value(0.5)
+}
+
+fn default_cutoff() -> ParamValue {
+    ParamValue::Value(1000.0)
+}
+
+// no resonance peak
+fn default_q() -> ParamValue {
+    ParamValue::Value(0.707)
+}
+
+/// The chain when run.toml lists no processors.
+fn default_processors() -> Vec<ProcessorConfig> {
+    vec![
+        ProcessorConfig::new("sounds", &[]),
+        ProcessorConfig::new("drums", &[]),
+        ProcessorConfig::new("dasp_test", &[("R", ParamValue::Signal("pitch".to_string()))]),
+        ProcessorConfig::new("general", &[("A", ParamValue::Signal("volume".to_string()))])
+    ]
+}
+
+/// Capturing the audio input into new sounds.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SamplingConfig {
+    /// keyboard keys that sample into a sound key while held, eg. F5 = "s1"
+    #[serde(default="HashMap::new")]
+    pub keys: HashMap<String, String>,
+    /// write captures as WAV next to sounds.toml
+    #[serde(default="default_false")]
+    pub save: bool,
+    /// level below which the start and end are trimmed off
+    #[serde(default="default_threshold")]
+    pub threshold: f32,
+    /// longest capture in seconds
+    #[serde(default="default_max_seconds")]
+    pub max_seconds: f32
+}
+impl Default for SamplingConfig {
+    fn default() -> Self {
+        Self { keys: HashMap::new(), save: false, threshold: default_threshold(), max_seconds: default_max_seconds() }
+    }
+}
+
+fn default_threshold() -> f32 {
+    0.01
+}
+
+fn default_max_seconds() -> f32 {
+    30.0
+}
+
+fn default_record_directory() -> String {
+    "recordings".to_string()
+}
+
+fn default_sample_rate() -> u32 {
+    44100
+}
+
+fn default_channels() -> usize {
+    2
 }
 
 fn empty_string() -> String {
@@ -64,10 +595,55 @@ fn default_seq() -> u8 {
     0
 }
 
+fn default_true() -> bool {
+    true
+}
+
 fn default_false() -> bool {
     false
 }
 
+fn default_gain() -> f32 {
+    1.0
+}
+
+fn default_zero() -> f32 {
+    0.0
+}
+
+fn default_attack() -> f32 {
+    0.001
+}
+
+fn default_sustain() -> f32 {
+    1.0
+}
+
+fn default_release() -> f32 {
+    0.01
+}
+
+// middle C
+fn default_root() -> u8 {
+    60
+}
+
+fn default_lokey() -> u8 {
+    0
+}
+
+fn default_hikey() -> u8 {
+    127
+}
+
+fn default_lovel() -> u8 {
+    0
+}
+
+fn default_hivel() -> u8 {
+    127
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct DeviceMap {
     sound: Option<String>,
@@ -80,15 +656,34 @@ pub struct DeviceMap {
     min: Option<f32>,
     max: Option<f32>,
     forward: Option<String>,
-    offset: Option<i8>
+    offset: Option<i8>,
+    lokey: Option<u8>,
+    hikey: Option<u8>,
+    root: Option<u8>,
+    bank: Option<u16>,
+    program: Option<u8>,
+    #[serde(default="default_false")]
+    record: bool,
+    sample: Option<String>,
+    synth: Option<String>
 }
 
 #[derive(Debug, Clone)]
 pub enum ParsedDeviceMap {
     SoundMap { key: String, note: u8, channel: Option<u8> },
+    /// plays the sound across lokey..=hikey, repitched relative to the root
+    /// note of the sound, or the root of the mapping when the sound has none.
+    /// For soundfonts `program` picks the preset until a Program Change arrives.
+    KeyRange { key: String, lokey: u8, hikey: u8, root: u8, channel: Option<u8>, bank: Option<u16>, program: Option<u8> },
     Aftertouch { signal: String, min: Option<f32>, max: Option<f32> },
     Controller { controller: u8, signal: String, min: Option<f32>, max: Option<f32> },
-    Forward { channel: Option<u8>, forward: String, offset: Option<i8> }
+    Forward { channel: Option<u8>, forward: String, offset: Option<i8> },
+    /// the note starts and stops recording the master output
+    Record { note: u8, channel: Option<u8> },
+    /// holding the note samples the audio input into the sound key
+    Sample { key: String, note: u8, channel: Option<u8> },
+    /// notes and pitch bend play the processor with this name, like a synth
+    Synth { name: String, channel: Option<u8> }
 }
 
 impl DeviceMap {
@@ -102,6 +697,23 @@ impl DeviceMap {
             return Some(ParsedDeviceMap::Forward { forward: forward.clone(), channel: self.channel, offset: self.offset })
         }
 
+        if self.record {
+            return Some(ParsedDeviceMap::Record { note: self.note.unwrap(), channel: self.channel })
+        }
+
+        if let Some(name) = &self.synth {
+            return Some(ParsedDeviceMap::Synth { name: name.clone(), channel: self.channel })
+        }
+
+        if let Some(key) = &self.sample {
+            return Some(ParsedDeviceMap::Sample { key: key.clone(), note: self.note.unwrap(), channel: self.channel })
+        }
+
+        if let (Some(key), Some(lokey), Some(hikey)) = (&self.sound, self.lokey, self.hikey) {
+            let root = self.root.unwrap_or(default_root());
+            return Some(ParsedDeviceMap::KeyRange { key: key.clone(), lokey, hikey, root, channel: self.channel, bank: self.bank, program: self.program })
+        }
+
         if let Some(key) = &self.sound {
             return Some(ParsedDeviceMap::SoundMap { key: key.clone(), note: self.note.unwrap(), channel: self.channel })
         }
@@ -115,62 +727,178 @@ impl DeviceMap {
 
 #[derive(Debug, Clone)]
 pub struct Sounds {
-    h: HashMap<String,Vec<Sound>>
+    h: HashMap<String,Vec<Sound>>,
+    modes: HashMap<String, SelectionMode>,
+    soundfonts: HashSet<String>,
+    // shared by every thread triggering sounds, so round-robin carries on between them
+    state: Arc<Mutex<HashMap<String, SelectionState>>>
 }
 
 impl Default for Sounds {
     fn default() -> Self {
-        Sounds { h: HashMap::default() }
+        Sounds {
+            h: HashMap::default(),
+            modes: HashMap::default(),
+            soundfonts: HashSet::default(),
+            state: Arc::new(Mutex::new(HashMap::default()))
+        }
     }
 }
 
 impl Sounds {
-    pub fn load(filename: &str) -> Sounds {
+    /// The sounds of a sounds file, and the soundfonts read for them so their
+    /// samples are decoded without reading the files again.
+    pub fn load(filename: &str) -> (Sounds, HashMap<String, sf2::SoundFont>) {
         let mut sounds = Sounds::default();
+        let mut fonts = HashMap::new();
         let s = fs::read_to_string(filename).unwrap_or("".to_string());
         let cfg: ConfigLoader = toml::from_str(&s).unwrap_or(ConfigLoader::default());
         cfg.sound.as_ref().unwrap_or(&vec![]).iter().for_each(|s| {
-            if !sounds.h.contains_key(&s.key) {
-                sounds.h.insert(s.key.clone(), vec![]);
-            }
-            if let Some(sound) = sounds.h.get_mut(&s.key) {
-                sound.push(s.clone());
+            let entries = match (&s.sfz, &s.sf2) {
+                (Some(path), _) => {
+                    // regions are layered by velocity and round-robin between seq positions
+                    sounds.modes.insert(s.key.clone(), SelectionMode::Velocity);
+                    sfz::load(path, s)
+                }
+                (None, Some(path)) => {
+                    sounds.soundfonts.insert(s.key.clone());
+                    match sf2::load(path, s) {
+                        Some((font, entries)) => {
+                            entries.iter().for_each(|e| {
+                                sounds.modes.insert(e.key.clone(), SelectionMode::Velocity);
+                            });
+                            fonts.insert(path.clone(), font);
+                            entries
+                        }
+                        None => vec![]
+                    }
+                }
+                (None, None) => vec![s.clone()]
+            };
+            for e in entries {
+                sounds.h.entry(e.key.clone()).or_insert(vec![]).push(e);
             }
         });
-        sounds
+        sounds.h.values_mut().for_each(|v| v.sort_by_key(|s| s.seq));
+        cfg.selection.as_ref().unwrap_or(&vec![]).iter().for_each(|s| {
+            sounds.modes.insert(s.key.clone(), s.mode);
+        });
+        (sounds, fonts)
     }
-    pub fn get(&self, key: &String, seq: u8) -> Option<Sound> {
-        let v = self.h.get(key);
-        match v.unwrap_or(&vec![]).get(seq as usize) {
-            Some(sound) => Some(sound.clone()),
-            None => None
+    /// Make `sound` the only sound under its key.
+    pub fn replace(&mut self, sound: Sound) {
+        self.modes.remove(&sound.key);
+        self.soundfonts.remove(&sound.key);
+        self.h.insert(sound.key.clone(), vec![sound]);
+    }
+
+    /// Soundfont keys play the preset picked for the channel, see `sf2::preset_key`.
+    pub fn is_soundfont(&self, key: &str) -> bool {
+        self.soundfonts.contains(key)
+    }
+    pub fn iter(&self) -> impl Iterator<Item=&Sound> {
+        self.h.values().flatten()
+    }
+    /// Pick the sound to play for a note on `key` according to the selection mode of the key.
+    pub fn select(&self, key: &str, note: u8, velocity: u8) -> Option<Sound> {
+        let sounds = self.h.get(key)?;
+        let mode = self.modes.get(key).cloned().unwrap_or_default();
+        let candidates: Vec<usize> = (0..sounds.len())
+            .filter(|i| sounds[*i].lokey <= note && note <= sounds[*i].hikey)
+            .filter(|i| match mode {
+                SelectionMode::Velocity => sounds[*i].lovel <= velocity && velocity <= sounds[*i].hivel,
+                _ => true
+            })
+            .collect();
+        if candidates.is_empty() {
+            return None;
         }
+
+        let mut states = self.state.lock().unwrap();
+        let state = states.entry(key.to_string()).or_default();
+        let n = candidates.len();
+        let pick = match mode {
+            SelectionMode::First => 0,
+            SelectionMode::RoundRobin | SelectionMode::Velocity => state.count % n,
+            SelectionMode::Random => random_range(0, n),
+            SelectionMode::RandomNoRepeat => {
+                match candidates.iter().position(|i| Some(*i) == state.last) {
+                    // pick from the others by skipping over the last one
+                    Some(last) if n > 1 => {
+                        let r = random_range(0, n - 1);
+                        if r >= last { r + 1 } else { r }
+                    }
+                    _ => random_range(0, n)
+                }
+            }
+        };
+        state.count += 1;
+        state.last = Some(candidates[pick]);
+        Some(sounds[candidates[pick]].clone())
     }
 }
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct ConfigLoader {
+    pub audio: Option<AudioConfig>,
+    pub record: Option<RecordConfig>,
+    pub sampling: Option<SamplingConfig>,
+    pub processor: Option<Vec<ProcessorConfig>>,
     pub device: Option<Vec<Device>>,
-    pub sound: Option<Vec<Sound>>
+    pub sound: Option<Vec<Sound>>,
+    pub selection: Option<Vec<Selection>>
 }
 impl Default for ConfigLoader {
     fn default() -> Self {
-        Self { device: None, sound: None }
+        Self { audio: None, record: None, sampling: None, processor: None, device: None, sound: None, selection: None }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
+    pub audio: AudioConfig,
+    pub record: RecordConfig,
+    pub sampling: SamplingConfig,
+    pub processors: Vec<ProcessorConfig>,
+    /// patch files of the processors, keyed by path
+    pub patches: HashMap<String, Patch>,
     pub devices: Vec<Device>,
-    pub sounds: Sounds
+    pub sounds: Sounds,
+    // where the sounds were loaded from, captures are saved next to it
+    pub sounds_file: String,
+    pub pool: Arc<SamplePool>
 }
 
 impl Config {
-    pub fn load() -> Self {
-        let s = fs::read_to_string("run.toml").unwrap_or("".to_string());
+    pub fn load(sample_rate: Option<u32>) -> Self {
+        Self::load_from("run.toml", "sounds.toml", sample_rate)
+    }
+
+    /// With `sample_rate`, the rate the output runs at, sounds are decoded at it
+    /// whatever run.toml asks for.
+    pub fn load_from(run: &str, sounds_file: &str, sample_rate: Option<u32>) -> Self {
+        let s = fs::read_to_string(run).unwrap_or("".to_string());
         let data: ConfigLoader = toml::from_str(&s).unwrap_or(ConfigLoader::default());
-        let sounds = Sounds::load("sounds.toml");
-        Self { devices: data.device.unwrap_or(vec![]), sounds }
+        let mut audio = data.audio.unwrap_or(AudioConfig::default());
+        if let Some(rate) = sample_rate {
+            audio.sample_rate = rate;
+        }
+        let (sounds, fonts) = Sounds::load(sounds_file);
+        // decode everything now so the audio thread never touches the disk
+        let pool = Arc::new(SamplePool::load(&sounds, &fonts, &audio));
+        let record = data.record.unwrap_or(RecordConfig::default());
+        let sampling = data.sampling.unwrap_or(SamplingConfig::default());
+        let processors = data.processor.unwrap_or(default_processors());
+        let patches = processors.iter()
+            .filter_map(|p| p.patch.as_ref())
+            .filter_map(|path| Some((path.clone(), Patch::load(path)?)))
+            .collect();
+        Self { audio, record, sampling, processors, patches, devices: data.device.unwrap_or(vec![]), sounds, sounds_file: sounds_file.to_string(), pool }
+    }
+
+    /// Where the processor named `name` is in `processors`, notes are sent to it by this.
+    pub fn processor_index(&self, name: &str) -> Option<usize> {
+        self.processors.iter().position(|p| p.name() == name)
     }
 
     pub fn hardware_inputs(&self) -> Vec<Device> {
@@ -185,6 +913,9 @@ impl Config {
     pub fn virtual_outputs(&self) -> Vec<Device> {
         self.devices.iter().filter(|v| v.output && v.device_type == DeviceType::Virtual).map(|v| v.clone()).collect::<Vec<Device>>()
     }
+    pub fn audio_inputs(&self) -> Vec<Device> {
+        self.devices.iter().filter(|v| v.input && v.device_type == DeviceType::Audio).map(|v| v.clone()).collect::<Vec<Device>>()
+    }
     pub fn mappings(&self) -> Vec<ParsedDeviceMap> {
         self.devices.iter()
         .filter(|d| d.mapping.is_some())
@@ -197,7 +928,30 @@ impl Config {
     }
 }
 
-pub fn config_watch_thread(events: message::Events) {
+/// Watch the patch files the processors of `cfg` name and stop watching the
+/// ones no longer named. `patches` are the watched files, canonical so events
+/// match them, with the path processors name them by.
+fn watch_patches(watcher: &mut RecommendedWatcher, cfg: &Config, patches: &mut Vec<(PathBuf, String)>) {
+    let named: Vec<&String> = cfg.processors.iter().filter_map(|p| p.patch.as_ref()).collect();
+    patches.retain(|(_, path)| {
+        let keep = named.contains(&path);
+        if !keep {
+            watcher.unwatch(path).ok();
+        }
+        keep
+    });
+    for path in named {
+        if patches.iter().any(|(_, p)| p == path) {
+            continue;
+        }
+        match watcher.watch(path, RecursiveMode::NonRecursive) {
+            Ok(_) => patches.extend(fs::canonicalize(path).ok().map(|file| (file, path.clone()))),
+            Err(e) => println!("Unable to watch {}: {:?}", path, e)
+        }
+    }
+}
+
+pub fn config_watch_thread(cfg: Arc<Config>, events: message::Events) {
     // Create a channel to receive the events.
     let (watch_tx, watch_rx) = channel();
 
@@ -209,14 +963,29 @@ pub fn config_watch_thread(events: message::Events) {
     // below will be monitored for changes.
     watcher.watch("run.toml", RecursiveMode::NonRecursive).unwrap();
     watcher.watch("sounds.toml", RecursiveMode::NonRecursive).unwrap();
+    let mut patches = vec![];
+    watch_patches(&mut watcher, &cfg, &mut patches);
 
     loop {
         match watch_rx.recv() {
             Ok(DebouncedEvent::NoticeWrite(event)) => {
                 println!("{:?}", event);
-                let cfg = Arc::new(config::Config::load());
-                events.midi_tx.send(midi::AppMidiEvent::ConfigUpdate(cfg.clone())).unwrap();
-                events.app_tx.send(message::Message::ConfigUpdate(cfg)).unwrap();
+                let file = fs::canonicalize(&event).ok();
+                let saved: Vec<String> = patches.iter().filter(|(f, _)| Some(f) == file.as_ref()).map(|(_, path)| path.clone()).collect();
+                if saved.is_empty() {
+                    // the output stays at the rate it was opened at
+                    let new_cfg = Arc::new(config::Config::load(Some(cfg.audio.sample_rate)));
+                    // patches added to or dropped from run.toml
+                    watch_patches(&mut watcher, &new_cfg, &mut patches);
+                    events.midi_tx.send(midi::AppMidiEvent::ConfigUpdate(new_cfg.clone())).unwrap();
+                    events.app_tx.send(message::Message::ConfigUpdate(new_cfg)).unwrap();
+                }
+                // a patch on its own only changes the chain, the sounds and devices stay as they are
+                for path in saved {
+                    if let Some(patch) = Patch::load(&path) {
+                        events.app_tx.send(message::Message::PatchUpdate(path, patch)).unwrap();
+                    }
+                }
             }
             Ok(event) => {
                 println!("{:?}", event);
@@ -227,3 +996,28 @@ pub fn config_watch_thread(events: message::Events) {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn velocity_curves() {
+        assert_eq!(VelocityCurve::Linear.gain(0), 0.0);
+        assert_eq!(VelocityCurve::Linear.gain(127), 1.0);
+        assert_eq!(VelocityCurve::Linear.gain(255), 1.0);
+        assert_eq!(VelocityCurve::Exponential.gain(0), 0.0);
+        assert_eq!(VelocityCurve::Exponential.gain(127), 1.0);
+        // 40dB down at the bottom
+        assert!((VelocityCurve::Exponential.gain(1) - 0.0104).abs() < 0.001);
+        assert_eq!(VelocityCurve::Fixed.gain(1), 1.0);
+    }
+
+    #[test]
+    fn drum_keys() {
+        assert_eq!(Drum::from_key("drum:kick"), Some(Drum::Kick));
+        assert_eq!(Drum::from_key("drum:openhat"), Some(Drum::OpenHat));
+        assert_eq!(Drum::from_key("drum:cowbell"), None);
+        assert_eq!(Drum::from_key("kick"), None);
+    }
+}
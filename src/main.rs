@@ -7,21 +7,34 @@ mod vsthost;
 mod audio;
 mod midi;
 mod message;
+mod sfz;
+mod sf2;
+mod render;
+mod sampling;
 
 struct Model {
     cfg: Arc<config::Config>,
     // stream: nannou_audio::Stream<audio::Audio>,
     threads: Vec<std::thread::JoinHandle<()>>,
     events: message::Events,
+    sampler: sampling::Sampler,
     pub midi: midi::MidiModel,
     pub audio: audio::Audio
 }
 impl Default for Model {
     fn default() -> Self {
-        let cfg = Arc::new(config::Config::load());
+        let mut cfg = config::Config::load(None);
         let (app_tx, app_rx) = unbounded();
 
-        let audio_model = audio::Audio::default();
+        let mut audio_model = audio::Audio::new(&cfg);
+        let sample_rate = audio_model.sample_rate();
+        if sample_rate != cfg.audio.sample_rate {
+            // sounds are decoded at the configured rate, load them again at the output's
+            println!("Audio output runs at {}Hz, converting sounds to it", sample_rate);
+            cfg = config::Config::load(Some(sample_rate));
+        }
+        audio_model.start(&cfg);
+        let cfg = Arc::new(cfg);
         let audio_rx = audio_model.audio_rx.clone();
         let audio_tx = audio_model.audio_tx.clone();
 
@@ -34,6 +47,7 @@ impl Default for Model {
             // inputs: vec![],
             // stream: audio_stream,
             threads: vec![],
+            sampler: sampling::Sampler::default(),
             midi: midi_model,
             audio: audio_model,
             events: message::Events {
@@ -56,13 +70,19 @@ impl Model {
             self.threads.push(std::thread::spawn(move || midi::recv_midi_thread(cfg, events)));
         }
         {
+            let cfg = self.cfg.clone();
             let events = self.events.clone();
-            self.threads.push(std::thread::spawn(move || config::config_watch_thread(events)));
+            self.threads.push(std::thread::spawn(move || config::config_watch_thread(cfg, events)));
         }
     }
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(|a| a.as_str()) == Some("render") {
+        render::main(&args[2..]);
+        return;
+    }
     nannou::app(model)
         .event(event)
         .update(update)
@@ -79,6 +99,11 @@ fn model(app: &App) -> Model {
     model
 }
 
+// Keys on the computer keyboard each get their own voice, like notes on a MIDI device.
+fn keyboard_voice(key: Key) -> audio::VoiceKey {
+    audio::VoiceKey::new("keyboard", 0, key as u8)
+}
+
 fn event(app: &App, model: &mut Model, event: Event) {
     // println!("E: {:?}", event);
     use Key::*;
@@ -86,6 +111,21 @@ fn event(app: &App, model: &mut Model, event: Event) {
         // Event::WindowEvent { id: _, simple: Some(KeyPressed(Key::Q)) } => {
         //     app.quit();
         // }
+        // F9 starts and stops recording what is played
+        Event::WindowEvent { id: _, simple: Some(KeyPressed(F9)) } => {
+            model.events.audio_tx.send(audio::AudioMessage::RecordToggle).unwrap();
+        }
+        Event::WindowEvent { id: _, simple: Some(KeyReleased(F9)) } => {}
+        // keys set up for sampling capture the audio input while held
+        Event::WindowEvent { id: _, simple: Some(KeyPressed(key)) } if model.cfg.sampling.keys.contains_key(&format!("{:?}", key)) => {
+            let sound = model.cfg.sampling.keys[&format!("{:?}", key)].clone();
+            let events = &model.events;
+            model.sampler.start(&model.cfg, &events.audio_tx, events.app_tx.clone(), &sound);
+        }
+        Event::WindowEvent { id: _, simple: Some(KeyReleased(key)) } if model.cfg.sampling.keys.contains_key(&format!("{:?}", key)) => {
+            let sound = model.cfg.sampling.keys[&format!("{:?}", key)].clone();
+            model.sampler.stop(&model.events.audio_tx, &sound);
+        }
         Event::WindowEvent { id: _, simple: Some(KeyPressed(key)) } if key >= Key1 && key <= Key0 => {
             let n1 = key as u32;
             let n0 = Key1 as u32;
@@ -93,7 +133,7 @@ fn event(app: &App, model: &mut Model, event: Event) {
             let s = format!("{}", n);
             println!("Key#: {:?}/{}", key, &s);
             // model.audio.sounds.on()
-            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &s, true);
+            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &s, keyboard_voice(key), true);
         }
         Event::WindowEvent { id: _, simple: Some(KeyReleased(key)) } if key >= Key1 && key <= Key0 => {
             let n1 = key as u32;
@@ -101,19 +141,19 @@ fn event(app: &App, model: &mut Model, event: Event) {
             let n = (n1 - n0 + 1) % 10;
             let s = format!("{}", n);
             println!("Key#: {:?}/{}", key, &s);
-            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &s, false);
+            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &s, keyboard_voice(key), false);
         }
         Event::WindowEvent { id: _, simple: Some(KeyPressed(key)) } => {
             let k = format!("{:?}", key);
             let v = key as u32;
             println!("Key: {}/{}", k, v);
-            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &k, true);
+            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &k, keyboard_voice(key), true);
         }
         Event::WindowEvent { id: _, simple: Some(KeyReleased(key)) } => {
             let k = format!("{:?}", key);
             let v = key as u32;
             println!("Key: {}/{}", k, v);
-            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &k, false);
+            audio::launch_sound(&model.cfg, model.events.audio_tx.clone(), &k, keyboard_voice(key), false);
         }
 
         // KeyReleased(_key) => {}
@@ -141,10 +181,39 @@ fn update(_app: &App, model: &mut Model, _update: Update) {
     match model.events.app_rx.try_recv() {
         Ok(message::Message::ConfigUpdate(new_cfg)) => {
             println!("New Config: {:?}", &new_cfg);
+            // a new pool can hold a new sample for a processor playing a sound
+            if new_cfg.processors != model.cfg.processors || new_cfg.patches != model.cfg.patches || !Arc::ptr_eq(&new_cfg.pool, &model.cfg.pool) {
+                let chain = audio::Chain::new(&new_cfg);
+                model.events.audio_tx.send(audio::AudioMessage::ChainUpdate(chain)).unwrap();
+            }
+            model.cfg = new_cfg;
             // model.inputs.drain(..).for_each(|i| i.close());
             // model.inputs = midi::scan_inputs(new_cfg, model.events.midi_tx.clone(), model.events.audio_tx.clone());
             // println!("Midi inputs reset")
         }
+        Ok(message::Message::PatchUpdate(path, patch)) => {
+            println!("New patch: {}", path);
+            let mut new_cfg = (*model.cfg).clone();
+            new_cfg.patches.insert(path, patch);
+            let chain = audio::Chain::new(&new_cfg);
+            model.events.audio_tx.send(audio::AudioMessage::ChainUpdate(chain)).unwrap();
+            model.cfg = Arc::new(new_cfg);
+        }
+        Ok(message::Message::Sampled(sound, sample)) => {
+            // merged here so takes that overlap, or a reload while sampling, are kept
+            println!("New sound: {}", sound.key);
+            let mut new_cfg = (*model.cfg).clone();
+            let mut pool = (*model.cfg.pool).clone();
+            pool.insert(&sound.path, sample);
+            new_cfg.pool = Arc::new(pool);
+            new_cfg.sounds.replace(sound);
+            let new_cfg = Arc::new(new_cfg);
+            let chain = audio::Chain::new(&new_cfg);
+            model.events.audio_tx.send(audio::AudioMessage::ChainUpdate(chain)).unwrap();
+            // the devices are the same, so the MIDI inputs stay open
+            model.events.midi_tx.send(midi::AppMidiEvent::SoundsUpdate(new_cfg.clone())).unwrap();
+            model.cfg = new_cfg;
+        }
         _ => ()
     }
 }
@@ -8,6 +8,10 @@ use super::audio;
 #[derive(Debug,Clone)]
 pub enum Message {
     ConfigUpdate(Arc<config::Config>),
+    /// a patch file that was saved, by the path processors name it with
+    PatchUpdate(String, config::Patch),
+    /// a take turned into a sound, merged into the config of the moment
+    Sampled(config::Sound, audio::Sample),
     // Quit
 }
 
@@ -11,6 +11,8 @@ use nannou::math::map_range;
 use super::config::*;
 use super::audio::*;
 use super::message;
+use super::sf2::preset_key;
+use super::sampling::Sampler;
 
 pub struct MidiModel {
     // pub(crate) inputs: Vec<NamedInputConnection>,
@@ -42,24 +44,41 @@ impl NamedOutputConnection {
 pub struct NamedInputConnection {
     pub key: String,
     pub name: String,
-    conn: midir::MidiInputConnection<MidiInputData>
+    conn: midir::MidiInputConnection<MidiInputData>,
+    cfg_tx: Sender<Arc<Config>>
 }
 impl NamedInputConnection {
     pub fn close(self) {
         self.conn.close();
     }
+
+    /// Hand the input a config with the same devices, it is picked up with the next message.
+    pub fn update(&self, cfg: Arc<Config>) {
+        self.cfg_tx.send(cfg).unwrap();
+    }
 }
 
 pub struct MidiInputData {
     pub midi_tx: Sender<AppMidiEvent>,
+    pub app_tx: Sender<message::Message>,
     pub audio_tx: Sender<AudioMessage>,
     pub device: Device,
     pub mappings: Vec<ParsedDeviceMap>,
     pub sound_mappings: HashMap<String, ParsedDeviceMap>,
-    pub cfg: Arc<Config>
+    pub cfg: Arc<Config>,
+    cfg_tx: Sender<Arc<Config>>,
+    cfg_rx: Receiver<Arc<Config>>,
+    // soundfont bank and program picked on each channel with Bank Select and Program Change
+    banks: HashMap<u8, u16>,
+    programs: HashMap<u8, u8>,
+    // CC0 is only Bank Select on devices playing a soundfont, elsewhere it can be mapped
+    soundfont: bool,
+    // the processor notes on a channel play, all channels when none is given
+    synths: Vec<(Option<u8>, usize)>,
+    sampler: Sampler
 }
 impl MidiInputData {
-    pub fn new(midi_tx: Sender<AppMidiEvent>, audio_tx: Sender<AudioMessage>, cfg: Arc<Config>, device: Device) -> Self {
+    pub fn new(midi_tx: Sender<AppMidiEvent>, app_tx: Sender<message::Message>, audio_tx: Sender<AudioMessage>, cfg: Arc<Config>, device: Device) -> Self {
         let mut sound_mappings = HashMap::new();
         let mappings = device.mappings();
         for m in mappings.clone() {
@@ -76,16 +95,85 @@ impl MidiInputData {
                 _ => ()
             }
         }
-        Self { midi_tx, audio_tx, device, mappings, cfg, sound_mappings}
+        let soundfont = maps_soundfont(&mappings, &cfg);
+        let synths = synths(&mappings, &cfg);
+        let (cfg_tx, cfg_rx) = unbounded();
+        Self { midi_tx, app_tx, audio_tx, device, mappings, cfg, cfg_tx, cfg_rx, sound_mappings, banks: HashMap::new(), programs: HashMap::new(), soundfont, synths, sampler: Sampler::default() }
+    }
+
+    /// Where to send configs with the same devices, see `NamedInputConnection::update`.
+    pub fn config_sender(&self) -> Sender<Arc<Config>> {
+        self.cfg_tx.clone()
+    }
+
+    /// Take the latest config sent, keeping the bank, program and sampling state.
+    fn update(&mut self) {
+        if let Some(cfg) = self.cfg_rx.try_iter().last() {
+            self.soundfont = maps_soundfont(&self.mappings, &cfg);
+            self.synths = synths(&self.mappings, &cfg);
+            self.cfg = cfg;
+        }
+    }
+
+    /// The sound key mapped to a note, and the root note for key ranges.
+    pub fn map_note(&self, channel: u8, note: u8) -> Option<(String, Option<u8>)> {
+        let key1 = format!("{}_{}", note, channel);
+        let key2 = format!("{}", note);
+        let mut found = None;
+        // the channel specific mapping wins over the note only mapping
+        for k in [key1, key2].iter() {
+            if let Some(ParsedDeviceMap::SoundMap { key, note: _, channel: _ }) = self.sound_mappings.get(k) {
+                found = Some((key.clone(), None, None, None));
+                break;
+            }
+        }
+        // then the first range that covers the note
+        let (key, root, bank, program) = found.or_else(|| self.mappings.iter().find_map(|m| {
+            match m {
+                ParsedDeviceMap::KeyRange { key, lokey, hikey, root, channel: c, bank, program }
+                    if c.map_or(true, |c| c == channel) && *lokey <= note && note <= *hikey => {
+                    Some((key.clone(), Some(*root), *bank, *program))
+                }
+                _ => None
+            }
+        }))?;
+
+        if self.cfg.sounds.is_soundfont(&key) {
+            // what was picked over MIDI wins over the mapping, channel 10 defaults to the drum bank
+            let bank = self.banks.get(&channel).cloned().or(bank).unwrap_or(if channel == 9 { 128 } else { 0 });
+            let program = self.programs.get(&channel).cloned().or(program).unwrap_or(0);
+            return Some((preset_key(&key, bank, program), root));
+        }
+        Some((key, root))
+    }
+
+    pub fn is_record_note(&self, channel: u8, note: u8) -> bool {
+        self.mappings.iter().any(|m| match m {
+            ParsedDeviceMap::Record { note: n, channel: c } => *n == note && c.map_or(true, |c| c == channel),
+            _ => false
+        })
+    }
+
+    /// The sound key holding the note samples into.
+    pub fn sample_key(&self, channel: u8, note: u8) -> Option<String> {
+        self.mappings.iter().find_map(|m| match m {
+            ParsedDeviceMap::Sample { key, note: n, channel: c } if *n == note && c.map_or(true, |c| c == channel) => Some(key.clone()),
+            _ => None
+        })
     }
 
-    pub fn send_sound(&self, id: u64, path: String) {
-        let r_sound = audrey::open(&path);
-        if let Ok(s) = r_sound {
-            println!("Send {} {}", id, path);
-            self.audio_tx.send(AudioMessage::SoundOn {id, sound: s}).unwrap();
+    /// The synth processor notes on the channel play.
+    pub fn synth(&self, channel: u8) -> Option<usize> {
+        self.synths.iter().find(|(c, _)| c.map_or(true, |c| c == channel)).map(|(_, index)| *index)
+    }
+
+    pub fn send_sound(&self, key: VoiceKey, sound: Sound, velocity: f32, rate: f64) {
+        if let Some(s) = self.cfg.pool.get(&sound.path) {
+            println!("Send {:?} {} {}", key, sound.path, velocity);
+            let playback = Playback::new(&sound, &s);
+            self.audio_tx.send(AudioMessage::SoundOn {key, sample: s, playback, velocity, rate}).unwrap();
         } else {
-            println!("Unable to open sound: {}", path);
+            println!("Sound not loaded: {}", sound.path);
         }
     }
 
@@ -93,27 +181,75 @@ impl MidiInputData {
         self.audio_tx.send(AudioMessage::SignalUpdate {key, value}).unwrap();
     }
 
-    pub fn handle(&self, ts: u64, message: &[u8]) {
+    pub fn handle(&mut self, ts: u64, message: &[u8]) {
+        self.update();
         let event = LiveEvent::parse(message).unwrap();
         println!("[{}] MidiRX({}): {:?}", ts, &self.device.key, event);
         match event {
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel }} if vel.as_int() > 0 && self.sample_key(channel.as_int(), note.as_int()).is_some() => {
+                let key = self.sample_key(channel.as_int(), note.as_int()).unwrap();
+                self.sampler.start(&self.cfg, &self.audio_tx, self.app_tx.clone(), &key);
+            }
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOff { key: note, vel: _ }} |
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel: _ }} if self.sample_key(channel.as_int(), note.as_int()).is_some() => {
+                let key = self.sample_key(channel.as_int(), note.as_int()).unwrap();
+                self.sampler.stop(&self.audio_tx, &key);
+            }
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel }} if vel.as_int() > 0 && self.synth(channel.as_int()).is_some() && !self.is_record_note(channel.as_int(), note.as_int()) => {
+                let synth = self.synth(channel.as_int()).unwrap();
+                let key = VoiceKey::new(&self.device.key, channel.as_int(), note.as_int());
+                let velocity = self.device.velocity.unwrap_or_default().gain(vel.as_int());
+                self.audio_tx.send(AudioMessage::NoteOn { synth, key, velocity }).unwrap();
+            }
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOff { key: note, vel: _ }} |
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel: _ }} if self.synth(channel.as_int()).is_some() && !self.is_record_note(channel.as_int(), note.as_int()) => {
+                let synth = self.synth(channel.as_int()).unwrap();
+                let key = VoiceKey::new(&self.device.key, channel.as_int(), note.as_int());
+                self.audio_tx.send(AudioMessage::NoteOff { synth, key }).unwrap();
+            }
+            LiveEvent::Midi { channel, message: MidiMessage::PitchBend { bend }} if self.synth(channel.as_int()).is_some() => {
+                let synth = self.synth(channel.as_int()).unwrap();
+                self.audio_tx.send(AudioMessage::PitchBend { synth, channel: channel.as_int(), bend: bend.as_f32() }).unwrap();
+            }
             LiveEvent::Midi { channel, message: MidiMessage::NoteOff { key: note, vel: _ }} => {
-                self.audio_tx.send(AudioMessage::SoundOff {id: 0}).unwrap();
+                let voice = VoiceKey::new(&self.device.key, channel.as_int(), note.as_int());
+                self.audio_tx.send(AudioMessage::SoundOff {key: voice}).unwrap();
+            }
+            // a NoteOn with zero velocity is a NoteOff
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel }} if vel.as_int() == 0 => {
+                let voice = VoiceKey::new(&self.device.key, channel.as_int(), note.as_int());
+                self.audio_tx.send(AudioMessage::SoundOff {key: voice}).unwrap();
             }
-            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel: _ }} => {
-                let key1 = format!("{}_{}", note, channel);
-                let key2 = format!("{}", note);
-                println!("x: {} {}", key1, key2);
-                for k in [key1, key2].iter() {
-                    if let Some(ParsedDeviceMap::SoundMap { key, note, channel }) = self.sound_mappings.get(&k.to_string()) {
-                        println!("map: {} {} {:?}", key, note, channel);
-                        if let Some(sound) = self.cfg.sounds.get(&key, 0) {
-                            self.send_sound(ts, sound.path);
-                        } 
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel: _ }} if self.is_record_note(channel.as_int(), note.as_int()) => {
+                self.audio_tx.send(AudioMessage::RecordToggle).unwrap();
+            }
+            LiveEvent::Midi { channel, message: MidiMessage::NoteOn { key: note, vel }} => {
+                if let Some((key, range_root)) = self.map_note(channel.as_int(), note.as_int()) {
+                    println!("map: {} {} {} {:?}", key, note, channel, range_root);
+                    if let Some(drum) = Drum::from_key(&key) {
+                        let velocity = self.device.velocity.unwrap_or_default().gain(vel.as_int());
+                        self.audio_tx.send(AudioMessage::DrumHit { drum, velocity }).unwrap();
+                    } else if let Some(sound) = self.cfg.sounds.select(&key, note.as_int(), vel.as_int()) {
+                        let voice = VoiceKey::new(&self.device.key, channel.as_int(), note.as_int());
+                        // the curve of the sound wins over the one of the device
+                        let curve = sound.velocity.or(self.device.velocity).unwrap_or_default();
+                        let velocity = curve.gain(vel.as_int());
+                        // so does the root note, without either the sound plays as recorded
+                        let rate = sound.root.or(range_root).map_or(1.0, |root| note_rate(note.as_int(), root));
+                        self.send_sound(voice, sound, velocity, rate);
                     }
                 }
             }
 
+            LiveEvent::Midi { channel, message: MidiMessage::ProgramChange { program }} => {
+                self.programs.insert(channel.as_int(), program.as_int());
+            }
+
+            // Bank Select, the most significant byte is the soundfont bank
+            LiveEvent::Midi { channel, message: MidiMessage::Controller { controller, value }} if controller.as_int() == 0 && self.soundfont => {
+                self.banks.insert(channel.as_int(), value.as_int() as u16);
+            }
+
             LiveEvent::Midi { channel, message: MidiMessage::ChannelAftertouch { vel }} => {
                 self.mappings.iter().for_each(|m| {
                     match m {
@@ -176,9 +312,33 @@ impl MidiEvent {
     }
 }
 
+/// The processors synth mappings play, by where they are in the config.
+fn synths(mappings: &[ParsedDeviceMap], cfg: &Config) -> Vec<(Option<u8>, usize)> {
+    mappings.iter().filter_map(|m| match m {
+        ParsedDeviceMap::Synth { name, channel } => match cfg.processor_index(name) {
+            Some(index) => Some((*channel, index)),
+            None => {
+                println!("No processor named {} to play", name);
+                None
+            }
+        },
+        _ => None
+    }).collect()
+}
+
+/// Whether a sound mapping plays a soundfont.
+fn maps_soundfont(mappings: &[ParsedDeviceMap], cfg: &Config) -> bool {
+    mappings.iter().any(|m| match m {
+        ParsedDeviceMap::SoundMap { key, .. } | ParsedDeviceMap::KeyRange { key, .. } => cfg.sounds.is_soundfont(key),
+        _ => false
+    })
+}
+
 #[derive(Debug,Clone)]
 pub enum AppMidiEvent {
     ConfigUpdate(Arc<Config>),
+    /// a config with new sounds but the same devices, the inputs keep their state
+    SoundsUpdate(Arc<Config>),
     Midi(MidiEvent)
 }
 
@@ -210,7 +370,7 @@ pub fn create_output_connections(cfg: Arc<Config>) -> Vec<NamedOutputConnection>
 
 pub fn recv_midi_thread(cfg: Arc<Config>, events: message::Events) {
     let mut output_connections = create_output_connections(cfg.clone());
-    let mut inputs = scan_inputs(cfg.clone(), events.midi_tx.clone(), events.audio_tx.clone());
+    let mut inputs = scan_inputs(cfg.clone(), events.midi_tx.clone(), events.app_tx.clone(), events.audio_tx.clone());
 
     loop {
         match events.midi_rx.try_recv() {
@@ -221,7 +381,10 @@ pub fn recv_midi_thread(cfg: Arc<Config>, events: message::Events) {
                 output_connections = create_output_connections(new_config.clone());
                 println!("Midi outputs reset");
                 inputs.drain(..).for_each(|i| i.close());
-                inputs = scan_inputs(new_config, events.midi_tx.clone(), events.audio_tx.clone());
+                inputs = scan_inputs(new_config, events.midi_tx.clone(), events.app_tx.clone(), events.audio_tx.clone());
+            }
+            Ok(AppMidiEvent::SoundsUpdate(new_config)) => {
+                inputs.iter().for_each(|i| i.update(new_config.clone()));
             }
             Ok(AppMidiEvent::Midi(e)) => {
                 for o in &mut output_connections {
@@ -234,7 +397,7 @@ pub fn recv_midi_thread(cfg: Arc<Config>, events: message::Events) {
     }
 }
 
-pub fn scan_inputs(cfg: Arc<Config>, midi_tx: Sender<AppMidiEvent>, audio_tx: Sender<AudioMessage>) -> Vec<NamedInputConnection> {
+pub fn scan_inputs(cfg: Arc<Config>, midi_tx: Sender<AppMidiEvent>, app_tx: Sender<message::Message>, audio_tx: Sender<AudioMessage>) -> Vec<NamedInputConnection> {
     let mut input_connections = vec![];
     let mut midi_in = MidiInput::new("Test").unwrap();
     midi_in.ignore(Ignore::None);
@@ -247,12 +410,13 @@ pub fn scan_inputs(cfg: Arc<Config>, midi_tx: Sender<AppMidiEvent>, audio_tx: Se
         if let Some(device) = inputs.get(&name) {
             println!("{}: {}", i, name);
             let midi_in = MidiInput::new("Test").unwrap();
-            let data = MidiInputData::new(midi_tx.clone(), audio_tx.clone(), cfg.clone(), device.clone());
+            let data = MidiInputData::new(midi_tx.clone(), app_tx.clone(), audio_tx.clone(), cfg.clone(), device.clone());
+            let cfg_tx = data.config_sender();
 
             let conn = midi_in.connect(&p.clone(), "forward", | ts, message, data| {
                 data.handle(ts, message);
             }, data).unwrap();
-            input_connections.push(NamedInputConnection { key: device.key.clone(), name: name.to_string(), conn});
+            input_connections.push(NamedInputConnection { key: device.key.clone(), name: name.to_string(), conn, cfg_tx });
         }
     }
 
@@ -260,7 +424,8 @@ pub fn scan_inputs(cfg: Arc<Config>, midi_tx: Sender<AppMidiEvent>, audio_tx: Se
         let v_midi_in = MidiInput::new("Test").unwrap();
         println!("Create Virtual Input {}", &device.name);
 
-        let data = MidiInputData::new(midi_tx.clone(), audio_tx.clone(), cfg.clone(), device.clone());
+        let data = MidiInputData::new(midi_tx.clone(), app_tx.clone(), audio_tx.clone(), cfg.clone(), device.clone());
+        let cfg_tx = data.config_sender();
         let conn = v_midi_in.create_virtual(&device.name, |ts, message, data| {
             let event = LiveEvent::parse(message).unwrap();
             println!("[{}] MidiRX({}): {:?}", ts, &data.device.key, event);
@@ -268,7 +433,7 @@ pub fn scan_inputs(cfg: Arc<Config>, midi_tx: Sender<AppMidiEvent>, audio_tx: Se
             //     tx.send(Message::Midi(MidiEvent { ts, a: message[0], b: message[1], c: message[2] })).unwrap();
             // }
         }, data).unwrap();
-        input_connections.push(NamedInputConnection { key: device.key.clone(), name: device.name.clone(), conn});
+        input_connections.push(NamedInputConnection { key: device.key.clone(), name: device.name.clone(), conn, cfg_tx });
     });
 
     input_connections
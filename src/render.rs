@@ -0,0 +1,179 @@
+use crossbeam::channel::unbounded;
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+use std::fs;
+use std::sync::Arc;
+
+use super::audio::{self, AudioData, Block};
+use super::config::Config;
+use super::midi::MidiInputData;
+
+/// Most frames rendered at a time, about what the output stream asks for.
+/// Blocks are cut short at each event so it starts on its own frame.
+const BLOCK_FRAMES: usize = 512;
+/// Default cap in seconds on rendering after the last event while sounds ring out.
+const DEFAULT_TAIL: f64 = 5.0;
+/// Peak level of a block taken as the end of the tail.
+const SILENCE: f32 = 0.0001;
+
+const USAGE: &str = "usage: audiotest render <song.mid> <out.wav> [--run run.toml] [--sounds sounds.toml] [--device key] [--tail seconds]";
+
+/// Headless bounce of a MIDI file: the events go through the mappings of a
+/// device in run.toml and the same processing chain as the output stream, as
+/// fast as it can be computed.
+pub fn main(args: &[String]) {
+    let mut positional = vec![];
+    let mut run = "run.toml".to_string();
+    let mut sounds = "sounds.toml".to_string();
+    let mut device_key = None;
+    let mut tail = DEFAULT_TAIL;
+    let mut i = 0;
+    while i < args.len() {
+        let value = args.get(i + 1).cloned();
+        match (args[i].as_str(), value) {
+            ("--run", Some(v)) => run = v,
+            ("--sounds", Some(v)) => sounds = v,
+            ("--device", Some(v)) => device_key = Some(v),
+            ("--tail", Some(v)) => tail = v.parse().unwrap_or(DEFAULT_TAIL),
+            (a, _) if !a.starts_with("--") => {
+                positional.push(a.to_string());
+                i += 1;
+                continue;
+            }
+            (a, None) => {
+                println!("Missing value for {}\n{}", a, USAGE);
+                return;
+            }
+            (a, _) => {
+                println!("Unknown option {}\n{}", a, USAGE);
+                return;
+            }
+        }
+        i += 2;
+    }
+    if positional.len() != 2 {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let cfg = Arc::new(Config::load_from(&run, &sounds, None));
+    // the named device, or the first one with mappings
+    let device = cfg.devices.iter()
+        .find(|d| device_key.as_ref().map_or(d.mapping.is_some(), |k| &d.key == k))
+        .cloned();
+    let device = match device {
+        Some(device) => device,
+        None => {
+            println!("No device to map the MIDI file with in {}", run);
+            return;
+        }
+    };
+
+    match render(&positional[0], &positional[1], cfg, device, tail) {
+        Ok(frames) => println!("Rendered {} frames to {}", frames, positional[1]),
+        Err(e) => println!("Render failed: {}", e)
+    }
+}
+
+fn render(midi_path: &str, wav_path: &str, cfg: Arc<Config>, device: crate::config::Device, tail: f64) -> Result<usize, String> {
+    let bytes = fs::read(midi_path).map_err(|e| format!("{}: {}", midi_path, e))?;
+    let smf = Smf::parse(&bytes).map_err(|e| format!("{}: {}", midi_path, e))?;
+    let events = timed_events(&smf);
+
+    let channels = cfg.audio.channels;
+    let sample_rate = cfg.audio.sample_rate;
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float
+    };
+    let mut writer = hound::WavWriter::create(wav_path, spec).map_err(|e| format!("{}: {}", wav_path, e))?;
+
+    let mut data = AudioData::new(&cfg);
+    // messages to MIDI outputs and the app are not sent anywhere when rendering
+    let (midi_tx, midi_rx) = unbounded();
+    let (app_tx, _app_rx) = unbounded();
+    let mut input = MidiInputData::new(midi_tx, app_tx, data.audio_tx.clone(), cfg, device);
+
+    let mut buf = vec![0.0; BLOCK_FRAMES * channels];
+    let mut position = 0;
+    // renders up to the frame `end` in blocks, the last one ending on it,
+    // and returns the peak of the last block written
+    let mut write_until = |data: &mut AudioData<f32>, position: &mut usize, end: usize| -> Result<f32, String> {
+        let mut peak = 0.0f32;
+        while *position < end {
+            let frames = (end - *position).min(BLOCK_FRAMES);
+            let samples = &mut buf[..frames * channels];
+            samples.iter_mut().for_each(|s| *s = 0.0);
+            audio::render(data, &mut Block::new(samples, channels, sample_rate));
+            peak = samples.iter().fold(0.0, |p, s| p.max(s.abs()));
+            for s in samples.iter() {
+                writer.write_sample(*s).map_err(|e| e.to_string())?;
+            }
+            *position += frames;
+        }
+        Ok(peak)
+    };
+
+    let mut message = vec![];
+    for (seconds, kind) in events {
+        // the block before the event ends on its frame, so the next one starts with it
+        write_until(&mut data, &mut position, (seconds * sample_rate as f64).round() as usize)?;
+        if let Some(event) = kind.as_live_event() {
+            message.clear();
+            event.write_std(&mut message).map_err(|e| e.to_string())?;
+            input.handle((seconds * 1_000_000.0) as u64, &message);
+            midi_rx.try_iter().for_each(drop);
+        }
+    }
+
+    // let the sounds ring out until every voice has ended and a block is silent
+    let end = position + (tail * sample_rate as f64) as usize;
+    loop {
+        let next = (position + BLOCK_FRAMES).min(end);
+        let peak = write_until(&mut data, &mut position, next)?;
+        if position >= end || (peak < SILENCE && !data.is_active() && data.audio_rx.is_empty()) {
+            break;
+        }
+    }
+    drop(write_until);
+    writer.finalize().map_err(|e| e.to_string())?;
+    Ok(position)
+}
+
+/// Channel events of all tracks in play order, with their time in seconds.
+fn timed_events<'a>(smf: &Smf<'a>) -> Vec<(f64, TrackEventKind<'a>)> {
+    let mut ticks = vec![];
+    for track in smf.tracks.iter() {
+        let mut tick: u64 = 0;
+        for event in track.iter() {
+            tick += event.delta.as_int() as u64;
+            ticks.push((tick, event.kind));
+        }
+    }
+    // stable, so events on the same tick keep their track order
+    ticks.sort_by_key(|(tick, _)| *tick);
+
+    // tempo changes apply to every track
+    let mut tempo = 500_000.0;
+    let mut last_tick = 0;
+    let mut seconds = 0.0;
+    let mut events = vec![];
+    for (tick, kind) in ticks {
+        match smf.header.timing {
+            Timing::Metrical(per_beat) => {
+                seconds += (tick - last_tick) as f64 * tempo / 1_000_000.0 / per_beat.as_int() as f64;
+            }
+            Timing::Timecode(fps, subframes) => {
+                seconds = tick as f64 / (fps.as_f32() as f64 * subframes as f64);
+            }
+        }
+        last_tick = tick;
+        match kind {
+            TrackEventKind::Meta(MetaMessage::Tempo(t)) => tempo = t.as_int() as f64,
+            TrackEventKind::Midi { .. } => events.push((seconds, kind)),
+            _ => ()
+        }
+    }
+    events
+}
@@ -0,0 +1,108 @@
+use crossbeam::channel::Sender;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::audio::{AudioMessage, Sample, Take};
+use super::config::{Config, Sound};
+use super::message;
+
+/// Seconds kept before the first and after the last sound above the threshold.
+const TRIM_MARGIN: f32 = 0.01;
+
+/// Starts and stops captures of the audio input into sound keys, one per
+/// thread that triggers them.
+pub struct Sampler {
+    // keys being captured, held keys repeat their presses
+    active: HashSet<String>
+}
+impl Default for Sampler {
+    fn default() -> Self {
+        Self { active: HashSet::new() }
+    }
+}
+impl Sampler {
+    pub fn start(&mut self, cfg: &Arc<Config>, audio_tx: &Sender<AudioMessage>, app_tx: Sender<message::Message>, key: &str) {
+        if cfg.audio_inputs().is_empty() {
+            println!("No audio input to sample {} from", key);
+            return;
+        }
+        if !self.active.insert(key.to_string()) {
+            return;
+        }
+        println!("Sampling into {}", key);
+        let max_samples = (cfg.sampling.max_seconds * cfg.audio.sample_rate as f32) as usize * cfg.audio.channels;
+        let (take, done) = Take::new(key, max_samples);
+        let cfg = cfg.clone();
+        // the take comes back once stopped, it is turned into a sound off the audio thread
+        std::thread::spawn(move || {
+            match done.recv() {
+                Ok(take) => finish(cfg, take, app_tx),
+                Err(_) => println!("No audio input to sample from")
+            }
+        });
+        audio_tx.send(AudioMessage::SampleStart { take }).unwrap();
+    }
+
+    pub fn stop(&mut self, audio_tx: &Sender<AudioMessage>, key: &str) {
+        if self.active.remove(key) {
+            audio_tx.send(AudioMessage::SampleStop { key: key.to_string() }).unwrap();
+        }
+    }
+}
+
+/// Turn the take into a sound and send it to the app, which makes it the only
+/// sound of its key in the config of the moment. Reloading sounds.toml drops it
+/// again unless it was saved and added there.
+fn finish(cfg: Arc<Config>, take: Take, app_tx: Sender<message::Message>) {
+    let channels = cfg.audio.channels;
+    let data = trim(&take.data, channels, cfg.sampling.threshold, (TRIM_MARGIN * cfg.audio.sample_rate as f32) as usize);
+    if data.is_empty() {
+        println!("Nothing above the threshold sampled into {}", take.key);
+        return;
+    }
+
+    let path = if cfg.sampling.save {
+        let dir = Path::new(&cfg.sounds_file).parent().unwrap_or(Path::new(""));
+        let path = dir.join(format!("sampled-{}.wav", take.key)).to_string_lossy().to_string();
+        match save(&path, data, channels, cfg.audio.sample_rate) {
+            Ok(_) => println!("Saved {}", path),
+            Err(e) => println!("Unable to save {}: {}", path, e)
+        }
+        path
+    } else {
+        format!("live:{}", take.key)
+    };
+
+    let sample = Sample::new(data, channels, cfg.audio.sample_rate, &cfg.audio);
+    println!("Sampled {} frames into {}", data.len() / channels, take.key);
+    app_tx.send(message::Message::Sampled(Sound { key: take.key, path, ..Sound::default() }, sample)).unwrap();
+}
+
+/// The frames from the first to the last one above the threshold, with a margin.
+fn trim(data: &[f32], channels: usize, threshold: f32, margin: usize) -> &[f32] {
+    let loud = |frame: &[f32]| frame.iter().any(|s| s.abs() > threshold);
+    let frames: Vec<&[f32]> = data.chunks_exact(channels).collect();
+    let first = match frames.iter().position(|f| loud(f)) {
+        Some(first) => first,
+        None => return &[]
+    };
+    let last = frames.iter().rposition(|f| loud(f)).unwrap_or(first);
+    let start = first.saturating_sub(margin);
+    let end = (last + 1 + margin).min(frames.len());
+    &data[start * channels..end * channels]
+}
+
+fn save(path: &str, data: &[f32], channels: usize, sample_rate: u32) -> Result<(), hound::Error> {
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float
+    };
+    let mut writer = hound::WavWriter::create(path, spec)?;
+    for s in data {
+        writer.write_sample(*s)?;
+    }
+    writer.finalize()
+}
@@ -0,0 +1,348 @@
+use std::collections::HashMap;
+use std::fs;
+
+use super::config::{PlayMode, Sound};
+
+// generator operators used from the SoundFont 2.01 spec
+const GEN_START_LOOP_OFFSET: u16 = 2;
+const GEN_END_LOOP_OFFSET: u16 = 3;
+const GEN_ATTACK_VOL_ENV: u16 = 34;
+const GEN_HOLD_VOL_ENV: u16 = 35;
+const GEN_DECAY_VOL_ENV: u16 = 36;
+const GEN_SUSTAIN_VOL_ENV: u16 = 37;
+const GEN_RELEASE_VOL_ENV: u16 = 38;
+const GEN_INSTRUMENT: u16 = 41;
+const GEN_KEY_RANGE: u16 = 43;
+const GEN_VEL_RANGE: u16 = 44;
+const GEN_START_LOOP_COARSE_OFFSET: u16 = 45;
+const GEN_END_LOOP_COARSE_OFFSET: u16 = 50;
+const GEN_COARSE_TUNE: u16 = 51;
+const GEN_SAMPLE_ID: u16 = 53;
+const GEN_SAMPLE_MODES: u16 = 54;
+const GEN_OVERRIDING_ROOT_KEY: u16 = 58;
+
+// sample types, the rom flag is ignored
+const SAMPLE_RIGHT: u16 = 2;
+const SAMPLE_LEFT: u16 = 4;
+
+/// Sound key of one preset of the soundfont loaded under `key`.
+pub fn preset_key(key: &str, bank: u16, program: u8) -> String {
+    format!("{}/{}/{}", key, bank, program)
+}
+
+/// Sample paths point into the soundfont as `file.sf2#index`, or
+/// `file.sf2#left+right` for a stereo pair.
+pub fn sample_path(file: &str, left: usize, right: Option<usize>) -> String {
+    match right {
+        Some(right) => format!("{}#{}+{}", file, left, right),
+        None => format!("{}#{}", file, left)
+    }
+}
+
+/// Split a path from `sample_path` into the file and the sample indices.
+pub fn split_path(path: &str) -> Option<(&str, usize, Option<usize>)> {
+    let (file, samples) = path.split_at(path.rfind('#')?);
+    let mut ids = samples[1..].split('+').map(|v| v.parse::<usize>());
+    let left = ids.next()?.ok()?;
+    let right = match ids.next() {
+        Some(id) => Some(id.ok()?),
+        None => None
+    };
+    Some((file, left, right))
+}
+
+pub struct SampleHeader {
+    start: usize,
+    end: usize,
+    start_loop: usize,
+    end_loop: usize,
+    pub sample_rate: u32,
+    original_pitch: u8,
+    link: usize,
+    sample_type: u16
+}
+
+struct Preset {
+    name: String,
+    program: u16,
+    bank: u16,
+    bag: usize
+}
+
+struct Instrument {
+    name: String,
+    bag: usize
+}
+
+#[derive(Clone, Copy)]
+struct Generator {
+    oper: u16,
+    amount: [u8; 2]
+}
+impl Generator {
+    fn signed(&self) -> i32 {
+        i16::from_le_bytes(self.amount) as i32
+    }
+    fn unsigned(&self) -> usize {
+        u16::from_le_bytes(self.amount) as usize
+    }
+    fn range(&self) -> (u8, u8) {
+        (self.amount[0], self.amount[1])
+    }
+}
+
+/// The parts of a SoundFont 2 file needed to play its presets.
+pub struct SoundFont {
+    presets: Vec<Preset>,
+    preset_bags: Vec<usize>,
+    preset_gens: Vec<Generator>,
+    instruments: Vec<Instrument>,
+    instrument_bags: Vec<usize>,
+    instrument_gens: Vec<Generator>,
+    pub samples: Vec<SampleHeader>,
+    data: Vec<i16>
+}
+
+impl SoundFont {
+    pub fn read(path: &str) -> Option<SoundFont> {
+        let bytes = fs::read(path).ok()?;
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"sfbk" {
+            println!("Not a soundfont: {}", path);
+            return None;
+        }
+        let mut chunks = HashMap::new();
+        read_chunks(&bytes[12..], &mut chunks);
+
+        let smpl = chunks.get(b"smpl")?;
+        let data = smpl.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+
+        // every list ends with a terminal record which is kept, it bounds the
+        // bags and generators of the last real entry
+        let presets = chunks.get(b"phdr")?.chunks_exact(38).map(|r| Preset {
+            name: name(&r[0..20]),
+            program: u16_at(r, 20),
+            bank: u16_at(r, 22),
+            bag: u16_at(r, 24) as usize
+        }).collect();
+        let instruments = chunks.get(b"inst")?.chunks_exact(22).map(|r| Instrument {
+            name: name(&r[0..20]),
+            bag: u16_at(r, 20) as usize
+        }).collect();
+        let bags = |id: &[u8; 4]| -> Option<Vec<usize>> {
+            Some(chunks.get(id)?.chunks_exact(4).map(|r| u16_at(r, 0) as usize).collect())
+        };
+        let gens = |id: &[u8; 4]| -> Option<Vec<Generator>> {
+            Some(chunks.get(id)?.chunks_exact(4).map(|r| Generator { oper: u16_at(r, 0), amount: [r[2], r[3]] }).collect())
+        };
+        let samples = chunks.get(b"shdr")?.chunks_exact(46).map(|r| SampleHeader {
+            start: u32_at(r, 20) as usize,
+            end: u32_at(r, 24) as usize,
+            start_loop: u32_at(r, 28) as usize,
+            end_loop: u32_at(r, 32) as usize,
+            sample_rate: u32_at(r, 36),
+            original_pitch: r[40],
+            link: u16_at(r, 42) as usize,
+            sample_type: u16_at(r, 44)
+        }).collect();
+
+        Some(SoundFont {
+            presets,
+            preset_bags: bags(b"pbag")?,
+            preset_gens: gens(b"pgen")?,
+            instruments,
+            instrument_bags: bags(b"ibag")?,
+            instrument_gens: gens(b"igen")?,
+            samples,
+            data
+        })
+    }
+
+    /// Frames of a sample, interleaved when a right channel is given.
+    pub fn sample_data(&self, left: usize, right: Option<usize>) -> Option<Vec<f32>> {
+        let channel = |id: usize| -> Option<Vec<f32>> {
+            let header = self.samples.get(id)?;
+            let pcm = self.data.get(header.start..header.end)?;
+            Some(pcm.iter().map(|v| *v as f32 / 32768.0).collect())
+        };
+        let left = channel(left)?;
+        match right.and_then(channel) {
+            Some(right) => Some(left.iter().zip(right.iter()).flat_map(|(l, r)| [*l, *r]).collect()),
+            None => Some(left)
+        }
+    }
+
+    /// Generators of each zone in the bag range, the first zone is global when
+    /// it doesn't end with `terminal`.
+    fn zones<'a>(&self, bags: &[usize], gens: &'a [Generator], first: usize, last: usize, terminal: u16) -> (Vec<&'a [Generator]>, Option<&'a [Generator]>) {
+        let mut zones = vec![];
+        let mut global = None;
+        for bag in first..last {
+            let (start, end) = match (bags.get(bag), bags.get(bag + 1)) {
+                (Some(start), Some(end)) if start <= end && *end <= gens.len() => (*start, *end),
+                _ => continue
+            };
+            let zone = &gens[start..end];
+            if zone.last().map(|g| g.oper) == Some(terminal) {
+                zones.push(zone);
+            } else if bag == first {
+                global = Some(zone);
+            }
+        }
+        (zones, global)
+    }
+
+    /// One sound per instrument zone of every preset, under the preset key.
+    fn sounds(&self, file: &str, base: &Sound) -> Vec<Sound> {
+        let mut sounds = vec![];
+        for p in self.presets.windows(2) {
+            let (preset, next) = (&p[0], &p[1]);
+            let key = preset_key(&base.key, preset.bank, preset.program as u8);
+            let (zones, global) = self.zones(&self.preset_bags, &self.preset_gens, preset.bag, next.bag, GEN_INSTRUMENT);
+            for zone in zones {
+                let pgens = merge(global, zone);
+                let instrument_id = pgens[&GEN_INSTRUMENT].unsigned();
+                let (instrument, next) = match (self.instruments.get(instrument_id), self.instruments.get(instrument_id + 1)) {
+                    (Some(i), Some(n)) => (i, n),
+                    _ => continue
+                };
+                let (izones, iglobal) = self.zones(&self.instrument_bags, &self.instrument_gens, instrument.bag, next.bag, GEN_SAMPLE_ID);
+                for izone in izones {
+                    let igens = merge(iglobal, izone);
+                    if let Some(sound) = self.zone_sound(file, &key, &pgens, &igens, base) {
+                        sounds.push(Sound { description: format!("{} {}", preset.name, instrument.name), ..sound });
+                    }
+                }
+            }
+        }
+        sounds
+    }
+
+    fn zone_sound(&self, file: &str, key: &str, pgens: &HashMap<u16, Generator>, igens: &HashMap<u16, Generator>, base: &Sound) -> Option<Sound> {
+        let id = igens[&GEN_SAMPLE_ID].unsigned();
+        let header = self.samples.get(id)?;
+        // a stereo pair plays from its left zone, with the right sample as the second channel
+        let right = match header.sample_type & 0x7fff {
+            SAMPLE_RIGHT => return None,
+            SAMPLE_LEFT => Some(header.link),
+            _ => None
+        };
+
+        // ranges narrow from the preset to the instrument
+        let range = |oper: u16| {
+            let (plo, phi) = pgens.get(&oper).map_or((0, 127), |g| g.range());
+            let (ilo, ihi) = igens.get(&oper).map_or((0, 127), |g| g.range());
+            (plo.max(ilo), phi.min(ihi))
+        };
+        let (lokey, hikey) = range(GEN_KEY_RANGE);
+        let (lovel, hivel) = range(GEN_VEL_RANGE);
+        if lokey > hikey || lovel > hivel {
+            return None;
+        }
+
+        // other preset generators add to the instrument ones
+        let value = |oper: u16, default: i32| {
+            igens.get(&oper).map_or(default, |g| g.signed()) + pgens.get(&oper).map_or(0, |g| g.signed())
+        };
+        let root_key = igens.get(&GEN_OVERRIDING_ROOT_KEY).map(|g| g.signed()).filter(|v| *v >= 0);
+        let root = root_key.unwrap_or(header.original_pitch as i32) - value(GEN_COARSE_TUNE, 0);
+
+        let mut envelope = base.envelope;
+        envelope.attack = timecents(value(GEN_ATTACK_VOL_ENV, -12000));
+        envelope.hold = timecents(value(GEN_HOLD_VOL_ENV, -12000));
+        envelope.decay = timecents(value(GEN_DECAY_VOL_ENV, -12000));
+        // sustain is an attenuation in centibels
+        envelope.sustain = 10f32.powf(-value(GEN_SUSTAIN_VOL_ENV, 0).max(0).min(1440) as f32 / 200.0);
+        envelope.release = timecents(value(GEN_RELEASE_VOL_ENV, -12000));
+
+        let mode = match igens.get(&GEN_SAMPLE_MODES).map(|g| g.unsigned() & 3) {
+            Some(1) | Some(3) => PlayMode::Loop,
+            _ => base.mode
+        };
+        let loop_start = header.start_loop as i64 - header.start as i64
+            + value(GEN_START_LOOP_OFFSET, 0) as i64 + value(GEN_START_LOOP_COARSE_OFFSET, 0) as i64 * 32768;
+        let loop_end = header.end_loop as i64 - header.start as i64
+            + value(GEN_END_LOOP_OFFSET, 0) as i64 + value(GEN_END_LOOP_COARSE_OFFSET, 0) as i64 * 32768;
+
+        Some(Sound {
+            key: key.to_string(),
+            path: sample_path(file, id, right),
+            lokey,
+            hikey,
+            root: Some(root.max(0).min(127) as u8),
+            lovel,
+            hivel,
+            mode,
+            loop_start: Some(loop_start.max(0) as usize),
+            loop_end: Some(loop_end.max(0) as usize),
+            envelope,
+            ..base.clone()
+        })
+    }
+}
+
+/// Read a soundfont, with every preset as sounds under `preset_key(base.key, bank, program)`.
+pub fn load(path: &str, base: &Sound) -> Option<(SoundFont, Vec<Sound>)> {
+    match SoundFont::read(path) {
+        Some(font) => {
+            let sounds = font.sounds(path, base);
+            println!("Loaded {} presets, {} zones from {}", font.presets.len().saturating_sub(1), sounds.len(), path);
+            Some((font, sounds))
+        }
+        None => {
+            println!("Unable to read soundfont {}", path);
+            None
+        }
+    }
+}
+
+/// Generators of a zone on top of the global zone.
+fn merge(global: Option<&[Generator]>, zone: &[Generator]) -> HashMap<u16, Generator> {
+    global.unwrap_or(&[]).iter().chain(zone.iter()).map(|g| (g.oper, *g)).collect()
+}
+
+fn timecents(tc: i32) -> f32 {
+    2f32.powf(tc as f32 / 1200.0)
+}
+
+/// Collect the chunks of a RIFF body, descending into LIST chunks.
+fn read_chunks<'a>(mut bytes: &'a [u8], chunks: &mut HashMap<[u8; 4], &'a [u8]>) {
+    while bytes.len() >= 8 {
+        let id = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        let size = (u32_at(bytes, 4) as usize).min(bytes.len() - 8);
+        let body = &bytes[8..8 + size];
+        if &id == b"LIST" && body.len() >= 4 {
+            read_chunks(&body[4..], chunks);
+        } else {
+            chunks.insert(id, body);
+        }
+        // chunks are padded to an even size
+        bytes = &bytes[(8 + size + (size & 1)).min(bytes.len())..];
+    }
+}
+
+fn name(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+fn u16_at(bytes: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([bytes[i], bytes[i + 1]])
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_sample_path() {
+        assert_eq!(split_path(&sample_path("a/piano.sf2", 3, None)), Some(("a/piano.sf2", 3, None)));
+        assert_eq!(split_path(&sample_path("a#b.sf2", 3, Some(4))), Some(("a#b.sf2", 3, Some(4))));
+        assert_eq!(split_path("piano.sf2"), None);
+        assert_eq!(split_path("piano.sf2#x"), None);
+        assert_eq!(split_path("piano.sf2#1+x"), None);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use super::config::{PlayMode, Sound};
+
+/// Opcodes set under a header, keyed by name.
+type Opcodes = HashMap<String, String>;
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Header(String),
+    Opcode(String, String)
+}
+
+/// Load the regions of an sfz instrument as sounds. `base` is the `[[sound]]`
+/// entry that pointed at the file, it gives the key and anything the regions
+/// don't set themselves.
+pub fn load(path: &str, base: &Sound) -> Vec<Sound> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(_) => {
+            println!("Unable to read sfz {}", path);
+            return vec![];
+        }
+    };
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    let mut control = Opcodes::new();
+    let mut global = Opcodes::new();
+    let mut master = Opcodes::new();
+    let mut group = Opcodes::new();
+    let mut region: Option<Opcodes> = None;
+    let mut header = String::new();
+    let mut sounds = vec![];
+
+    for token in tokenize(&text) {
+        match token {
+            Token::Header(name) => {
+                if let Some(r) = region.take() {
+                    // each level overrides the ones above it
+                    let mut opcodes = global.clone();
+                    opcodes.extend(master.clone());
+                    opcodes.extend(group.clone());
+                    opcodes.extend(r);
+                    sounds.extend(region_sound(&opcodes, &control, dir, base));
+                }
+                match name.as_str() {
+                    "global" => {
+                        global.clear();
+                        master.clear();
+                        group.clear();
+                    }
+                    "master" => {
+                        master.clear();
+                        group.clear();
+                    }
+                    "group" => group.clear(),
+                    "region" => region = Some(Opcodes::new()),
+                    _ => ()
+                }
+                header = name;
+            }
+            Token::Opcode(name, value) => {
+                let opcodes = match header.as_str() {
+                    "control" => &mut control,
+                    "global" => &mut global,
+                    "master" => &mut master,
+                    "group" => &mut group,
+                    "region" => region.get_or_insert(Opcodes::new()),
+                    // curves, effects and the like are not supported
+                    _ => continue
+                };
+                opcodes.insert(name, value);
+            }
+        }
+    }
+    if let Some(r) = region.take() {
+        let mut opcodes = global;
+        opcodes.extend(master);
+        opcodes.extend(group);
+        opcodes.extend(r);
+        sounds.extend(region_sound(&opcodes, &control, dir, base));
+    }
+    println!("Loaded {} regions from {}", sounds.len(), path);
+    sounds
+}
+
+fn region_sound(opcodes: &Opcodes, control: &Opcodes, dir: &Path, base: &Sound) -> Option<Sound> {
+    let sample = opcodes.get("sample")?;
+    // generated waveforms like *sine have no file
+    if sample.starts_with('*') {
+        return None;
+    }
+    // release triggers would need the note off, only attacks are played
+    if let Some(trigger) = opcodes.get("trigger") {
+        if trigger != "attack" {
+            return None;
+        }
+    }
+    let default_path = control.get("default_path").cloned().unwrap_or_default();
+    let file = format!("{}{}", default_path, sample).replace('\\', "/");
+    let path = dir.join(file).to_string_lossy().to_string();
+
+    let note = |name: &str| opcodes.get(name).and_then(|v| parse_note(v));
+    let number = |name: &str| opcodes.get(name).and_then(|v| v.parse::<f32>().ok());
+
+    let key = note("key");
+    let lokey = note("lokey").or(key).unwrap_or(base.lokey);
+    let hikey = note("hikey").or(key).unwrap_or(base.hikey);
+    // sfz repitches from middle C unless told otherwise
+    let keycenter = note("pitch_keycenter").or(key).unwrap_or(60) as i32;
+    let transpose = number("transpose").unwrap_or(0.0) as i32;
+    let root = if number("pitch_keytrack") == Some(0.0) {
+        None
+    } else {
+        Some((keycenter - transpose).max(0).min(127) as u8)
+    };
+
+    let mut envelope = base.envelope;
+    envelope.attack = number("ampeg_attack").unwrap_or(envelope.attack);
+    envelope.hold = number("ampeg_hold").unwrap_or(envelope.hold);
+    envelope.decay = number("ampeg_decay").unwrap_or(envelope.decay);
+    envelope.sustain = number("ampeg_sustain").map(|v| v / 100.0).unwrap_or(envelope.sustain);
+    envelope.release = number("ampeg_release").unwrap_or(envelope.release);
+
+    let mode = match opcodes.get("loop_mode").map(|v| v.as_str()) {
+        Some("one_shot") => PlayMode::OneShot,
+        Some("no_loop") => PlayMode::Gate,
+        Some("loop_continuous") | Some("loop_sustain") => PlayMode::Loop,
+        _ => base.mode
+    };
+    let frames = |a: &str, b: &str| opcodes.get(a).or(opcodes.get(b)).and_then(|v| v.parse::<usize>().ok());
+
+    Some(Sound {
+        key: base.key.clone(),
+        path,
+        sfz: None,
+        lokey,
+        hikey,
+        root,
+        lovel: number("lovel").map(|v| v as u8).unwrap_or(base.lovel),
+        hivel: number("hivel").map(|v| v as u8).unwrap_or(base.hivel),
+        seq: number("seq_position").map(|v| (v as u8).saturating_sub(1)).unwrap_or(base.seq),
+        mode,
+        loop_start: frames("loop_start", "loopstart").or(base.loop_start),
+        // the sfz end frame is played too
+        loop_end: frames("loop_end", "loopend").map(|v| v + 1).or(base.loop_end),
+        envelope,
+        ..base.clone()
+    })
+}
+
+/// Note numbers or names like c4, c#4 and eb-1, where c4 is middle C.
+fn parse_note(value: &str) -> Option<u8> {
+    if let Ok(n) = value.parse::<u8>() {
+        return Some(n.min(127));
+    }
+    let lower = value.to_lowercase();
+    let mut chars = lower.chars().peekable();
+    let mut semitone: i32 = match chars.next()? {
+        'c' => 0,
+        'd' => 2,
+        'e' => 4,
+        'f' => 5,
+        'g' => 7,
+        'a' => 9,
+        'b' => 11,
+        _ => return None
+    };
+    match chars.peek() {
+        Some('#') => {
+            semitone += 1;
+            chars.next();
+        }
+        Some('b') => {
+            semitone -= 1;
+            chars.next();
+        }
+        _ => ()
+    }
+    let octave: i32 = chars.collect::<String>().parse().ok()?;
+    let note = (octave + 1) * 12 + semitone;
+    if (0..=127).contains(&note) { Some(note as u8) } else { None }
+}
+
+fn tokenize(text: &str) -> Vec<Token> {
+    let text = strip_comments(text);
+    let mut defines: Vec<(String, String)> = vec![];
+    let mut tokens = vec![];
+    for line in text.lines() {
+        let mut line = line.trim().to_string();
+        if line.starts_with("#define") {
+            let mut parts = line["#define".len()..].split_whitespace();
+            if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+                defines.push((name.to_string(), value.to_string()));
+            }
+            continue;
+        }
+        if line.starts_with('#') {
+            println!("Unsupported sfz directive: {}", line);
+            continue;
+        }
+        for (name, value) in defines.iter() {
+            line = line.replace(name.as_str(), value);
+        }
+        tokenize_line(&line, &mut tokens);
+    }
+    tokens
+}
+
+fn tokenize_line(line: &str, tokens: &mut Vec<Token>) {
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        if rest.starts_with('<') {
+            let end = match rest.find('>') {
+                Some(end) => end,
+                None => {
+                    println!("Unterminated sfz header, skipping: {}", line);
+                    return;
+                }
+            };
+            tokens.push(Token::Header(rest[1..end].trim().to_string()));
+            rest = rest[end + 1..].trim_start();
+            continue;
+        }
+        let eq = match rest.find('=') {
+            Some(eq) => eq,
+            None => return
+        };
+        let name = rest[..eq].trim().to_string();
+        let after = &rest[eq + 1..];
+        // values can hold spaces (file names), they run up to the next opcode or header
+        let end = value_end(after);
+        tokens.push(Token::Opcode(name, after[..end].trim().to_string()));
+        rest = after[end..].trim_start();
+    }
+}
+
+fn value_end(value: &str) -> usize {
+    let bytes = value.as_bytes();
+    for (i, b) in bytes.iter().enumerate() {
+        if *b == b'<' {
+            return i;
+        }
+        if b.is_ascii_whitespace() {
+            let word = &value[i..].trim_start();
+            let ident = word.bytes().take_while(|c| c.is_ascii_alphanumeric() || *c == b'_').count();
+            if ident > 0 && word.as_bytes().get(ident) == Some(&b'=') {
+                return i;
+            }
+        }
+    }
+    value.len()
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        if rest.starts_with("//") {
+            rest = &rest[rest.find('\n').unwrap_or(rest.len())..];
+        } else if rest.starts_with("/*") {
+            rest = rest.find("*/").map_or("", |end| &rest[end + 2..]);
+        } else {
+            let c = rest.chars().next().unwrap();
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<Token> {
+        let mut tokens = vec![];
+        tokenize_line(line, &mut tokens);
+        tokens
+    }
+
+    fn opcode(name: &str, value: &str) -> Token {
+        Token::Opcode(name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn headers_and_opcodes() {
+        assert_eq!(tokens("<region> key=60 sample=a.wav"), vec![
+            Token::Header("region".to_string()),
+            opcode("key", "60"),
+            opcode("sample", "a.wav")
+        ]);
+    }
+
+    #[test]
+    fn values_with_spaces() {
+        assert_eq!(tokens("sample=Grand Piano C4.wav lokey=60<region>"), vec![
+            opcode("sample", "Grand Piano C4.wav"),
+            opcode("lokey", "60"),
+            Token::Header("region".to_string())
+        ]);
+    }
+
+    #[test]
+    fn unterminated_header() {
+        assert_eq!(tokens("key=60 <region"), vec![opcode("key", "60")]);
+        assert_eq!(tokens("<"), vec![]);
+    }
+}